@@ -0,0 +1,93 @@
+use anyhow::{Context, Result, bail};
+use nextbus_sign_server::gtfs::Feed;
+use nextbus_sign_server::stop_map::StopMap;
+
+const USAGE: &str = "\
+Usage: nextbus-gtfs-import FEED MAP [options]
+
+Makes each sign's stop configuration from a GTFS zip and a map of its stops to signs (see the
+stop_map module docs). Prints the stops as JSON unless they're pushed.
+
+Options:
+    --push URL            Set the stops on each sign through the server's HTTP API at URL,
+                          e.g. http://localhost:8080";
+
+struct Opts {
+    feed: String,
+    map: String,
+    push: Option<String>,
+}
+
+impl Opts {
+    fn parse() -> Result<Self> {
+        let mut paths = Vec::new();
+        let mut push = None;
+
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--help" | "-h" => {
+                    println!("{USAGE}");
+                    std::process::exit(0);
+                }
+                "--push" => {
+                    let Some(url) = args.next() else {
+                        bail!("Missing value for {arg}.\n\n{USAGE}");
+                    };
+                    push = Some(url.trim_end_matches('/').to_string());
+                }
+                _ if arg.starts_with("--") => bail!("Unknown option {arg}.\n\n{USAGE}"),
+                _ => paths.push(arg),
+            }
+        }
+
+        let [feed, map] = <[String; 2]>::try_from(paths)
+            .map_err(|_| anyhow::anyhow!("Expected a feed and a map.\n\n{USAGE}"))?;
+        Ok(Opts { feed, map, push })
+    }
+}
+
+fn main() {
+    env_logger::init();
+    if let Err(e) = inner() {
+        log::error!("Fatal error: {e:?}");
+        std::process::exit(1);
+    }
+
+    fn inner() -> Result<()> {
+        let opts = Opts::parse()?;
+
+        let map = StopMap::load(&opts.map)
+            .with_context(|| format!("Couldn't load stop map from {}", opts.map))?;
+        let feed =
+            Feed::open(&opts.feed).with_context(|| format!("Couldn't read feed {}", opts.feed))?;
+        let signs = feed.stop_configs(&map)?;
+
+        let Some(server) = opts.push else {
+            println!("{}", serde_json::to_string_pretty(&signs)?);
+            return Ok(());
+        };
+
+        let mut failed = 0;
+        for (sign, stops) in &signs {
+            let url = format!("{server}/signs/{sign}/stops");
+            let result = ureq::put(&url)
+                .content_type("application/json")
+                .send(&serde_json::to_vec(stops)?)
+                .and_then(|mut resp| resp.body_mut().read_to_string());
+
+            match result {
+                Ok(deliveries) => println!("{sign}: {} stop(s), {deliveries}", stops.len()),
+                Err(e) => {
+                    log::error!("Couldn't set stops on {sign}: {e}");
+                    failed += 1;
+                }
+            }
+        }
+
+        if failed > 0 {
+            bail!("Couldn't set stops on {failed} sign(s).");
+        }
+        Ok(())
+    }
+}
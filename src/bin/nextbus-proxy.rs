@@ -0,0 +1,131 @@
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+
+use anyhow::{Context, Result, bail};
+use nextbus_sign_server::proxy::{self, Rule};
+use nextbus_sign_server::record::Recorder;
+
+const USAGE: &str = "\
+Usage: nextbus-proxy --upstream ADDR [options]
+
+Accepts sign connections and relays them to another server, logging every message.
+
+Options:
+    --upstream ADDR       Server to relay to (required)
+    --listen ADDR         Address to accept signs on (default 0.0.0.0:4502)
+    --rules FILE          Rules to drop, replace or inject messages (see proxy module docs)
+    --record DIR          Keep a per-sign recording of the relayed traffic in DIR";
+
+struct Opts {
+    upstream: String,
+    listen: String,
+    rules: Vec<Rule>,
+    record_dir: Option<PathBuf>,
+}
+
+impl Opts {
+    fn parse() -> Result<Self> {
+        let mut upstream = None;
+        let mut opts = Opts {
+            upstream: String::new(),
+            listen: "0.0.0.0:4502".to_string(),
+            rules: Vec::new(),
+            record_dir: None,
+        };
+
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            if arg == "--help" || arg == "-h" {
+                println!("{USAGE}");
+                std::process::exit(0);
+            }
+
+            let Some(value) = args.next() else {
+                bail!("Missing value for {arg}.\n\n{USAGE}");
+            };
+
+            match arg.as_str() {
+                "--upstream" => upstream = Some(value),
+                "--listen" => opts.listen = value,
+                "--rules" => {
+                    let text = std::fs::read_to_string(&value)
+                        .with_context(|| format!("Couldn't read rules from {value}"))?;
+                    opts.rules = proxy::parse_rules(&text)?;
+                }
+                "--record" => opts.record_dir = Some(PathBuf::from(value)),
+                _ => bail!("Unknown option {arg}.\n\n{USAGE}"),
+            }
+        }
+
+        let Some(upstream) = upstream else {
+            bail!("--upstream is required.\n\n{USAGE}");
+        };
+        opts.upstream = upstream;
+
+        Ok(opts)
+    }
+}
+
+fn main() {
+    env_logger::init();
+    if let Err(e) = inner() {
+        log::error!("Fatal error: {e:?}");
+        std::process::exit(1);
+    }
+
+    fn inner() -> Result<()> {
+        let opts = Arc::new(Opts::parse()?);
+        let rules = Arc::new(opts.rules.clone());
+
+        let listener = TcpListener::bind(&opts.listen)
+            .with_context(|| format!("Couldn't listen on {}", opts.listen))?;
+        log::info!(
+            "Relaying signs on {} to {} with {} rule(s).",
+            opts.listen,
+            opts.upstream,
+            rules.len()
+        );
+
+        for stream in listener.incoming() {
+            match stream {
+                Err(e) => log::warn!("Can't get stream: {e}"),
+                Ok(c) => {
+                    let (opts, rules) = (opts.clone(), rules.clone());
+                    thread::spawn(move || {
+                        if let Err(e) = handle(c, &opts, rules) {
+                            log::warn!("Couldn't relay connection: {e:?}");
+                        }
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn handle(sign: TcpStream, opts: &Opts, rules: Arc<Vec<Rule>>) -> Result<()> {
+    let addr = sign.peer_addr()?;
+    log::info!("Relaying connection from: {addr}");
+
+    let upstream = TcpStream::connect(&opts.upstream)
+        .with_context(|| format!("Couldn't connect to upstream {}", opts.upstream))?;
+
+    let recorder = match &opts.record_dir {
+        Some(dir) => {
+            let path = dir.join(format!("{}.rec", addr.ip()));
+            Some(
+                Recorder::create(&path, addr)
+                    .with_context(|| format!("Couldn't open recording at {}", path.display()))?,
+            )
+        }
+        None => None,
+    };
+
+    proxy::relay(sign, upstream, rules, recorder)?;
+    log::info!("Connection from {addr} finished.");
+
+    Ok(())
+}
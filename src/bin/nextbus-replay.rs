@@ -0,0 +1,104 @@
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Local, Offset, Utc};
+use chrono_tz::Tz;
+use nextbus_sign_server::record::{self, Session};
+use nextbus_sign_server::replay::{Mismatch, replay};
+use nextbus_sign_server::tz;
+
+const USAGE: &str = "Usage: nextbus-replay [--tz ZONE] <recording>...
+
+Options:
+    --tz ZONE    The IANA time zone the sign was given (default: the local offset)";
+
+/// Replay recordings made with the `record_dir` setting against the current response logic, and fail if
+/// any response differs from what was recorded.
+fn main() {
+    env_logger::init();
+    if let Err(e) = inner() {
+        log::error!("Fatal error: {e:?}");
+        std::process::exit(1);
+    }
+
+    fn inner() -> Result<()> {
+        let mut zone = None;
+        let mut paths = Vec::new();
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--tz" => {
+                    let name = args
+                        .next()
+                        .with_context(|| format!("--tz needs a value\n\n{USAGE}"))?;
+                    zone = Some(
+                        name.parse::<Tz>()
+                            .map_err(|e| anyhow::anyhow!("Bad time zone {name}: {e}"))?,
+                    );
+                }
+                "--help" | "-h" => {
+                    println!("{USAGE}");
+                    return Ok(());
+                }
+                _ => paths.push(arg),
+            }
+        }
+        if paths.is_empty() {
+            bail!("{USAGE}");
+        }
+
+        let mut failures = 0;
+        for path in paths {
+            let sessions =
+                record::read(&path).with_context(|| format!("Couldn't read recording {path}"))?;
+
+            for session in sessions {
+                let mismatches = replay(&session.records, &posix_tz(&session, zone));
+                if mismatches.is_empty() {
+                    println!("ok   {path}: session {}", session.header);
+                    continue;
+                }
+
+                failures += 1;
+                println!("FAIL {path}: session {}", session.header);
+                for mismatch in mismatches {
+                    match mismatch {
+                        Mismatch::Differs {
+                            at,
+                            recorded,
+                            replayed,
+                        } => println!(
+                            "    at {:.6}s: recorded {recorded:?}, replayed {replayed:?}",
+                            at.as_secs_f64()
+                        ),
+                        Mismatch::Unexpected { replayed } => {
+                            println!("    never sent: {replayed:?}")
+                        }
+                        Mismatch::Unanswered { at, recorded } => println!(
+                            "    at {:.6}s: recorded {recorded:?}, replayed nothing",
+                            at.as_secs_f64()
+                        ),
+                    }
+                }
+            }
+        }
+
+        if failures > 0 {
+            bail!("{failures} session(s) didn't replay cleanly.");
+        }
+        Ok(())
+    }
+}
+
+/// The time zone the server would have given the sign when the session started, as it works it out
+/// in `mark_clock`.
+fn posix_tz(session: &Session, zone: Option<Tz>) -> String {
+    let started = session
+        .header
+        .split_whitespace()
+        .next()
+        .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+        .map_or_else(Utc::now, |time| time.with_timezone(&Utc));
+    match zone {
+        Some(zone) => tz::posix_tz(zone, started),
+        None => tz::posix_fixed(started.with_timezone(&Local).offset().fix()),
+    }
+}
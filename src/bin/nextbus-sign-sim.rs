@@ -0,0 +1,452 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::net::TcpStream;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result, bail};
+use chrono::Timelike;
+use crossbeam::channel::{self, select};
+use nextbus_sign_server::msg::Message;
+use nextbus_sign_server::msg::app_running::AppRunningReason;
+use nextbus_sign_server::msg::content::PayloadType;
+use nextbus_sign_server::msg::content_schedule::Schedule;
+
+const USAGE: &str = "\
+Usage: nextbus-sign-sim [options]
+
+Connects to a sign server and plays the firmware's side of the protocol.
+
+Options:
+    --server ADDR         Server to connect to (default 127.0.0.1:4502)
+    --ping-secs N         Seconds between pings (default 30)
+    --drift N             Clock drift to report on sync, in seconds (default 0)
+    --content-error N     Error code for AckContent (default 0)
+    --delete-error N      Error code for AckContentDelete (default 0)
+    --schedule-error N    Error code for AckContentSchedule (default 0)
+    --count-error N       Error code for AckContentCount (default 0)
+    --sync-error N        Error code for AckSyncClock (default 0)
+    --cfg-error N         Error code for AckGetCfgParam/AckSetCfgParam (default 0)
+    --stop-error N        Error code for AckStopCfg (default 0)
+    --drop-after N        Disconnect after receiving N firmware chunks";
+
+/// Error codes to answer each kind of command with. Zero is success.
+#[derive(Default)]
+struct Errors {
+    content: u8,
+    delete: u8,
+    schedule: u8,
+    count: u8,
+    sync: u8,
+    cfg: u8,
+    stop: u8,
+}
+
+struct Opts {
+    server: String,
+    ping_every: Duration,
+    drift_sec: u16,
+    errors: Errors,
+    drop_after: Option<usize>,
+}
+
+impl Opts {
+    fn parse() -> Result<Self> {
+        let mut opts = Opts {
+            server: "127.0.0.1:4502".to_string(),
+            ping_every: Duration::from_secs(30),
+            drift_sec: 0,
+            errors: Errors::default(),
+            drop_after: None,
+        };
+
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            if arg == "--help" || arg == "-h" {
+                println!("{USAGE}");
+                std::process::exit(0);
+            }
+
+            let Some(value) = args.next() else {
+                bail!("Missing value for {arg}.\n\n{USAGE}");
+            };
+            let code = || {
+                value
+                    .parse::<u8>()
+                    .with_context(|| format!("Bad error code for {arg}: {value}"))
+            };
+
+            match arg.as_str() {
+                "--server" => opts.server = value.clone(),
+                "--ping-secs" => {
+                    opts.ping_every = Duration::from_secs(
+                        value
+                            .parse()
+                            .with_context(|| format!("Bad ping interval: {value}"))?,
+                    )
+                }
+                "--drift" => {
+                    opts.drift_sec = value
+                        .parse()
+                        .with_context(|| format!("Bad drift: {value}"))?
+                }
+                "--content-error" => opts.errors.content = code()?,
+                "--delete-error" => opts.errors.delete = code()?,
+                "--schedule-error" => opts.errors.schedule = code()?,
+                "--count-error" => opts.errors.count = code()?,
+                "--sync-error" => opts.errors.sync = code()?,
+                "--cfg-error" => opts.errors.cfg = code()?,
+                "--stop-error" => opts.errors.stop = code()?,
+                "--drop-after" => {
+                    opts.drop_after = Some(
+                        value
+                            .parse()
+                            .with_context(|| format!("Bad chunk count: {value}"))?,
+                    )
+                }
+                _ => bail!("Unknown option {arg}.\n\n{USAGE}"),
+            }
+        }
+
+        Ok(opts)
+    }
+}
+
+/// What the simulated sign currently holds.
+#[derive(Default)]
+struct Sign {
+    content: BTreeMap<u16, Content>,
+    stops: BTreeMap<u8, String>,
+    cfg: HashMap<u8, u8>,
+    tz: Option<String>,
+    /// Our own clock reading when the last MarkClock arrived.
+    mark: Option<(u8, u64)>,
+    firmware: Option<Firmware>,
+}
+
+/// Firmware received since the last reboot.
+struct Firmware {
+    next_seq: u8,
+    chunks: usize,
+    bytes: usize,
+}
+
+struct Content {
+    text: String,
+    count_impressions: bool,
+    schedule: Option<Vec<Schedule>>,
+    /// Impressions per hour of the day.
+    impressions: [u16; 24],
+}
+
+fn main() {
+    env_logger::init();
+    if let Err(e) = inner() {
+        log::error!("Fatal error: {e:?}");
+        std::process::exit(1);
+    }
+
+    fn inner() -> Result<()> {
+        let opts = Opts::parse()?;
+
+        let stream = TcpStream::connect(&opts.server)
+            .with_context(|| format!("Couldn't connect to {}", opts.server))?;
+        log::info!("Connected to {}", opts.server);
+
+        let (s, r) = nextbus_sign_server::run(stream, None);
+
+        s.send(Message::AppRunning {
+            seq_num: 0,
+            reason: AppRunningReason::Powerup,
+        })?;
+        s.send(Message::DebugMsg {
+            msg: "nextbus-sign-sim powered up".to_string(),
+        })?;
+
+        let ticker = channel::tick(opts.ping_every);
+        let mut ping_seq: u8 = 0;
+        let mut sign = Sign::default();
+        render(&sign);
+
+        loop {
+            select!(
+                recv(ticker) -> _ => {
+                    ping_seq = ping_seq.wrapping_add(1);
+                    s.send(Message::Ping { seq_num: ping_seq })?;
+                    sign.tick();
+                    render(&sign);
+                },
+                recv(r) -> msg => {
+                    let Ok(msg) = msg else {
+                        bail!("Connection to server closed.");
+                    };
+                    log::info!("Recv'd: {msg:?}");
+
+                    for resp in sign.respond(msg, &opts) {
+                        s.send(resp)?;
+                    }
+                    render(&sign);
+
+                    if let (Some(n), Some(firmware)) = (opts.drop_after, &sign.firmware)
+                        && firmware.chunks >= n
+                    {
+                        bail!("Dropping the connection after {n} firmware chunks.");
+                    }
+                },
+            );
+        }
+    }
+}
+
+impl Sign {
+    fn respond(&mut self, msg: Message, opts: &Opts) -> Vec<Message> {
+        let errors = &opts.errors;
+
+        match msg {
+            Message::Pong { .. } => vec![],
+            Message::MarkClock { sequence } => {
+                self.mark = Some((sequence, now_sec()));
+                vec![Message::AckMarkClock { seq_num: sequence }]
+            }
+            Message::SyncClock {
+                seq_num,
+                epoch_time_sec,
+                tz,
+                ..
+            } => {
+                let drift_sec = match self.mark {
+                    Some((seq, at)) if seq == seq_num => {
+                        let drift = at.abs_diff(epoch_time_sec.into());
+                        let drift = drift + u64::from(opts.drift_sec);
+                        drift.try_into().unwrap_or(u16::MAX)
+                    }
+                    _ => {
+                        log::warn!("SyncClock {seq_num} doesn't match our last mark.");
+                        opts.drift_sec
+                    }
+                };
+                self.tz = Some(tz.clone());
+
+                vec![
+                    Message::AckSyncClock {
+                        mark_id: seq_num,
+                        error: errors.sync,
+                        drift_sec,
+                    },
+                    Message::DebugMsg {
+                        msg: format!("clock synced, tz {tz}, drift {drift_sec}s"),
+                    },
+                ]
+            }
+            Message::ContentMsg {
+                content_id,
+                count_impressions,
+                payloads,
+                ..
+            } => {
+                if errors.content == 0 {
+                    let text = payloads
+                        .iter()
+                        .filter(|(t, _)| *t == PayloadType::Msg)
+                        .map(|(_, p)| String::from_utf8_lossy(p).into_owned())
+                        .collect::<Vec<_>>()
+                        .join("\n");
+                    let old = self.content.remove(&content_id);
+
+                    self.content.insert(
+                        content_id,
+                        Content {
+                            text,
+                            count_impressions,
+                            schedule: old.and_then(|c| c.schedule),
+                            impressions: [0; 24],
+                        },
+                    );
+                }
+
+                vec![Message::AckContent {
+                    content_id,
+                    error: errors.content,
+                }]
+            }
+            Message::ContentDelete { content_id } => {
+                if errors.delete == 0 {
+                    self.content.remove(&content_id);
+                }
+                vec![Message::AckContentDelete {
+                    content_id,
+                    error: errors.delete,
+                }]
+            }
+            Message::ContentSchedule {
+                content_id,
+                start_stop_times,
+                ..
+            } => {
+                if errors.schedule == 0
+                    && let Some(content) = self.content.get_mut(&content_id)
+                {
+                    content.schedule = start_stop_times;
+                }
+                vec![Message::AckContentSchedule {
+                    content_id,
+                    error: errors.schedule,
+                }]
+            }
+            Message::ContentCount { content_id } => {
+                let data = match self.content.get(&content_id) {
+                    Some(c) => c.impressions,
+                    None => [0; 24],
+                };
+                vec![Message::AckContentCount {
+                    content_id,
+                    error: errors.count,
+                    data,
+                }]
+            }
+            Message::GetCfgParam { param } => vec![Message::AckGetCfgParam {
+                param,
+                error: errors.cfg,
+                value: self.cfg.get(&param).copied().unwrap_or(0),
+            }],
+            Message::SetCfgParam { param, value } => {
+                if errors.cfg == 0 {
+                    self.cfg.insert(param, value);
+                }
+                vec![Message::AckSetCfgParam {
+                    param,
+                    error: errors.cfg,
+                    value: self.cfg.get(&param).copied().unwrap_or(0),
+                }]
+            }
+            Message::ResetCfgParams => {
+                self.cfg.clear();
+                vec![Message::AckResetCfgParams]
+            }
+            Message::StopCfg { stop_id, title, .. } => {
+                if errors.stop == 0 {
+                    self.stops.insert(stop_id, title);
+                }
+                vec![Message::AckStopCfg {
+                    stop_id,
+                    error: errors.stop,
+                }]
+            }
+            Message::ClearStopCfg => {
+                self.stops.clear();
+                vec![Message::AckClearStopCfg]
+            }
+            Message::FirmwareCode {
+                seq,
+                dest_addr,
+                num_bytes,
+                ..
+            } => {
+                let firmware = self.firmware.get_or_insert(Firmware {
+                    next_seq: seq,
+                    chunks: 0,
+                    bytes: 0,
+                });
+                let mut resp = vec![];
+                if seq != firmware.next_seq {
+                    resp.push(Message::DebugMsg {
+                        msg: format!(
+                            "firmware chunk {seq} out of sequence, expected {}",
+                            firmware.next_seq
+                        ),
+                    });
+                }
+                log::info!("Firmware chunk {seq}: {num_bytes} bytes at {dest_addr:#06x}");
+                firmware.next_seq = seq.wrapping_add(1);
+                firmware.chunks += 1;
+                firmware.bytes += usize::from(num_bytes);
+                resp
+            }
+            Message::Reboot => {
+                let reason = match self.firmware {
+                    Some(_) => AppRunningReason::NewFirmware,
+                    None => AppRunningReason::ServerOrder,
+                };
+                *self = Sign::default();
+                vec![Message::AppRunning { seq_num: 0, reason }]
+            }
+            msg => vec![Message::DebugMsg {
+                msg: format!("sim doesn't handle message type {}", msg.get_type()),
+            }],
+        }
+    }
+
+    /// Count an impression for everything currently on display.
+    fn tick(&mut self) {
+        let hour = chrono::Local::now().hour() as usize;
+        let now_ms = now_sec() * 1_000;
+
+        for content in self.content.values_mut() {
+            if content.count_impressions && content.showing(now_ms) {
+                content.impressions[hour] = content.impressions[hour].saturating_add(1);
+            }
+        }
+    }
+}
+
+impl Content {
+    fn showing(&self, now_ms: u64) -> bool {
+        match &self.schedule {
+            None => true,
+            Some(windows) => windows
+                .iter()
+                .any(|Schedule { start, stop }| (*start..*stop).contains(&now_ms)),
+        }
+    }
+}
+
+fn now_sec() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Draw the sign's face, followed by its stop configuration.
+fn render(sign: &Sign) {
+    const WIDTH: usize = 40;
+    let now_ms = now_sec() * 1_000;
+
+    let mut out = String::from("\x1b[2J\x1b[H");
+    out += &format!("+{}+\n", "-".repeat(WIDTH));
+    let mut shown = 0;
+    for (id, content) in &sign.content {
+        if !content.showing(now_ms) {
+            continue;
+        }
+        shown += 1;
+
+        for line in content.text.lines() {
+            let line: String = line.chars().take(WIDTH - 7).collect();
+            out += &format!("|{id:>5}: {line:<w$}|\n", w = WIDTH - 7);
+        }
+    }
+    if shown == 0 {
+        out += &format!("|{:^WIDTH$}|\n", "(blank)");
+    }
+    out += &format!("+{}+\n", "-".repeat(WIDTH));
+
+    out += &format!("tz: {}\n", sign.tz.as_deref().unwrap_or("(not synced)"));
+    if let Some(firmware) = &sign.firmware {
+        out += &format!(
+            "firmware: {} bytes in {} chunks\n",
+            firmware.bytes, firmware.chunks
+        );
+    }
+    for (id, title) in &sign.stops {
+        out += &format!("stop {id}: {title}\n");
+    }
+    if !sign.cfg.is_empty() {
+        let mut cfg: Vec<_> = sign.cfg.iter().collect();
+        cfg.sort();
+        out += &format!("cfg: {cfg:?}\n");
+    }
+
+    let mut stdout = std::io::stdout().lock();
+    let _ = stdout.write_all(out.as_bytes());
+    let _ = stdout.flush();
+}
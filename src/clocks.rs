@@ -0,0 +1,115 @@
+//! How each sign's clock syncs have gone, to spot signs whose clocks won't keep time.
+//!
+//! Every `AckSyncClock` is kept, up to a limit per sign, across reconnects but only in memory, so
+//! the history starts over when the server restarts. A sign is drifting if its last successful
+//! sync found its clock further out than the configured threshold, and failing if its last few
+//! syncs all reported errors.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Mutex, MutexGuard};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::config::ClockConfig;
+use crate::msg::Message;
+use crate::registry::SignId;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Sync {
+    pub at: DateTime<Utc>,
+    pub drift_sec: u16,
+    pub error: u8,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ClockReport {
+    pub sign: SignId,
+    pub last_sync: Option<DateTime<Utc>>,
+    /// As of the last sync without an error.
+    pub drift_sec: Option<u16>,
+    /// How many syncs in a row have failed.
+    pub failures: usize,
+    pub drifting: bool,
+    pub failing: bool,
+}
+
+pub struct Clocks {
+    config: ClockConfig,
+    signs: Mutex<BTreeMap<SignId, VecDeque<Sync>>>,
+}
+
+impl Clocks {
+    pub fn new(config: &ClockConfig) -> Self {
+        Clocks {
+            config: config.clone(),
+            signs: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Record a sync, if that's what a sign's message reports.
+    pub fn observe(&self, sign: &str, msg: &Message) {
+        let Message::AckSyncClock {
+            error, drift_sec, ..
+        } = msg
+        else {
+            return;
+        };
+
+        let mut signs = self.xs();
+        let history = signs.entry(sign.to_string()).or_default();
+        history.push_back(Sync {
+            at: Utc::now(),
+            drift_sec: *drift_sec,
+            error: *error,
+        });
+        while history.len() > self.config.history {
+            history.pop_front();
+        }
+
+        if *error != 0 {
+            log::warn!("{sign} failed to sync its clock: error {error}");
+        } else if *drift_sec > self.config.max_drift_secs {
+            log::warn!("{sign}'s clock was {drift_sec}s out.");
+        }
+    }
+
+    /// A sign's syncs, oldest first. `None` if it hasn't synced.
+    pub fn history(&self, sign: &str) -> Option<Vec<Sync>> {
+        Some(self.xs().get(sign)?.iter().cloned().collect())
+    }
+
+    /// `None` if the sign hasn't synced.
+    pub fn report(&self, sign: &str) -> Option<ClockReport> {
+        self.xs().get(sign).map(|h| self.summarise(sign, h))
+    }
+
+    pub fn reports(&self) -> Vec<ClockReport> {
+        self.xs()
+            .iter()
+            .map(|(sign, h)| self.summarise(sign, h))
+            .collect()
+    }
+
+    fn summarise(&self, sign: &str, history: &VecDeque<Sync>) -> ClockReport {
+        let drift_sec = history
+            .iter()
+            .rev()
+            .find(|s| s.error == 0)
+            .map(|s| s.drift_sec);
+        let failures = history.iter().rev().take_while(|s| s.error != 0).count();
+
+        ClockReport {
+            sign: sign.to_string(),
+            last_sync: history.back().map(|s| s.at),
+            drift_sec,
+            failures,
+            drifting: drift_sec.is_some_and(|d| d > self.config.max_drift_secs),
+            failing: failures >= self.config.max_failures,
+        }
+    }
+
+    fn xs(&self) -> MutexGuard<'_, BTreeMap<SignId, VecDeque<Sync>>> {
+        self.signs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
@@ -0,0 +1,507 @@
+//! Server configuration, read from a TOML file.
+//!
+//! ```toml
+//! # Keep a per-sign recording of all traffic, to check against later with `nextbus-replay`.
+//! record_dir = "/var/lib/nextbus/recordings"
+//! # Where to keep what each sign should be showing, to push again when it reconnects.
+//! state_path = "/var/lib/nextbus/desired.json"
+//! # Where to keep impression counts, and how often to collect them.
+//! impressions_path = "/var/lib/nextbus/impressions.csv"
+//! count_interval_secs = 900
+//! # A library of sounds for content and stops to play, the address signs reach this server's
+//! # HTTP API at to fetch them, and how often to look for changes to them.
+//! sound_dir = "/var/lib/nextbus/sounds"
+//! sound_url = "http://10.0.0.1:8080"
+//! sound_scan_secs = 60
+//! # Pronunciations to build announcement phonemes from (see the phonemes module).
+//! lexicon = "/etc/nextbus/lexicon.toml"
+//! # The IANA time zone signs are told they're in, unless they or a group they're in have their
+//! # own. Otherwise they get the server's current UTC offset, without any daylight saving rules.
+//! # Schedule times without an offset, and `{time}` in arrival templates, are in the sign's zone.
+//! time_zone = "America/Los_Angeles"
+//!
+//! # Realtime sources to show arrival countdowns from, with a stop map (see the stop_map module)
+//! # saying which of their stops are on which signs.
+//! [[predictions]]
+//! kind = "gtfs-rt"
+//! feed = "https://agency.example/gtfs-rt/tripupdates.pb"
+//! # Optional, for route names and headsigns.
+//! static_feed = "/var/lib/nextbus/gtfs.zip"
+//! stop_map = "/etc/nextbus/stops.toml"
+//! interval_secs = 30
+//!
+//! [[predictions]]
+//! kind = "nextbus"
+//! base_url = "https://retro.umoiq.com/service/publicXMLFeed"
+//! agency = "sf-muni"
+//! # Route tags as in the signs' stop configuration, and the feed's stop tags.
+//! stops = [{ route = "N", stop = "5205" }, { route = "L", stop = "5205" }]
+//! stop_map = "/etc/nextbus/muni-stops.toml"
+//!
+//! [[predictions]]
+//! kind = "siri"
+//! # A StopMonitoring endpoint, or a file.
+//! feed = "https://siri.example/stop-monitoring"
+//! # Optional; if given, a StopMonitoringRequest for these is sent rather than a plain GET.
+//! monitoring_refs = ["490000077E"]
+//! # Optional; the zone of any times the feed gives without an offset, by default the server's.
+//! time_zone = "Europe/London"
+//! stop_map = "/etc/nextbus/siri-stops.toml"
+//!
+//! # How arrivals are laid out on signs without a layout of their own (see the
+//! # predictions::template module).
+//! [arrivals]
+//! template = "{route:>3} {headsign:<14} {minutes}min"
+//! width = 24
+//! rows = 3
+//! # "time" or "route".
+//! sort = "time"
+//!
+//! # How often to sync signs' clocks, besides whenever they connect, and when to flag them: when
+//! # their clocks are more than max_drift_secs out, or their last max_failures syncs failed.
+//! # The last `history` syncs are kept for each sign.
+//! [clock]
+//! sync_secs = 60
+//! max_drift_secs = 5
+//! max_failures = 3
+//! history = 1440
+//!
+//! # How firmware updates are sent (see the firmware module): chunks of at most chunk_bytes,
+//! # pace_ms apart, checked by reading a config parameter every `batch` chunks. An update fails
+//! # once max_retries checks in a row go unanswered, or if the sign hasn't come back with its new
+//! # firmware reboot_timeout_secs after being told to reboot.
+//! [firmware]
+//! chunk_bytes = 1024
+//! batch = 16
+//! pace_ms = 20
+//! max_retries = 3
+//! reboot_timeout_secs = 300
+//!
+//! # Names for config parameters. These are the parameters an audit reads from every sign.
+//! [params]
+//! brightness = 3
+//! volume = 7
+//!
+//! # Sets of parameter values, assigned to signs directly or through a group.
+//! [profiles.indoor]
+//! brightness = 40
+//! volume = 10
+//!
+//! [profiles.outdoor]
+//! brightness = 255
+//! volume = 30
+//!
+//! [[signs]]
+//! id = "union-station-1"
+//! ip = "10.0.4.21"
+//! station = "union"
+//! platform = "1"
+//! lines = ["red", "blue"]
+//! profile = "outdoor"
+//! time_zone = "America/Denver"
+//!
+//! # The sign's stops, until they're changed through the API.
+//! [[signs.stops]]
+//! stop_id = 1
+//! title = "Union Station"
+//! route_tag = "red"
+//! zero_countdown_msg = "Arriving"
+//! # From the sound library; snd_url and snd_md5 are filled in.
+//! sound = "union-chime.wav"
+//!
+//! [signs.arrivals]
+//! template = "{route:>4} {headsign:<20} {minutes:>2}min"
+//! width = 32
+//! lines.blue = "{route:>4} {headsign:<20} {minutes:>2}min*"
+//!
+//! [[signs]]
+//! id = "union-station-2"
+//! mac = "00:1b:c5:00:12:9f"
+//! station = "union"
+//! platform = "2"
+//! lines = ["red"]
+//!
+//! # Signs matching every selector given, plus any listed by id.
+//! [groups.union-red]
+//! station = "union"
+//! line = "red"
+//! signs = ["10.0.9.3"]
+//! profile = "indoor"
+//! time_zone = "America/Denver"
+//! ```
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use chrono_tz::Tz;
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::firmware::MAX_CHUNK;
+use crate::predictions::template::Layout;
+use crate::sounds::URL_OVERHEAD;
+use crate::stops::{self, Stop};
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Failed i/o: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid config: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("Invalid config: {0}")]
+    Invalid(String),
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub record_dir: Option<PathBuf>,
+    pub state_path: Option<PathBuf>,
+    pub impressions_path: Option<PathBuf>,
+    /// How often to collect impression counts, by default every 15 minutes.
+    pub count_interval_secs: Option<u64>,
+    pub sound_dir: Option<PathBuf>,
+    pub sound_url: Option<String>,
+    /// How often to look for changed sounds, by default every minute.
+    pub sound_scan_secs: Option<u64>,
+    pub lexicon: Option<PathBuf>,
+    pub time_zone: Option<Tz>,
+    pub clock: ClockConfig,
+    pub firmware: FirmwareConfig,
+    pub predictions: Vec<PredictionConfig>,
+    pub arrivals: Layout,
+    /// Config parameter numbers, by name.
+    pub params: BTreeMap<String, u8>,
+    /// Parameter values by parameter name, by profile name.
+    pub profiles: BTreeMap<String, BTreeMap<String, u8>>,
+    pub signs: Vec<SignConfig>,
+    pub groups: BTreeMap<String, GroupConfig>,
+}
+
+/// Where arrival predictions come from.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum PredictionConfig {
+    GtfsRt {
+        /// The `TripUpdates` feed's URL, or a file.
+        feed: String,
+        static_feed: Option<PathBuf>,
+        stop_map: PathBuf,
+        interval_secs: Option<u64>,
+    },
+    #[serde(rename = "nextbus")]
+    NextBus {
+        /// Where the `publicXMLFeed` endpoint is.
+        base_url: String,
+        agency: String,
+        stops: Vec<RouteStop>,
+        stop_map: PathBuf,
+        interval_secs: Option<u64>,
+    },
+    Siri {
+        feed: String,
+        #[serde(default)]
+        monitoring_refs: Vec<String>,
+        /// Who to say is asking, by default `nextbus-sign-server`.
+        requestor_ref: Option<String>,
+        time_zone: Option<Tz>,
+        stop_map: PathBuf,
+        interval_secs: Option<u64>,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteStop {
+    pub route: String,
+    pub stop: String,
+}
+
+impl PredictionConfig {
+    pub fn stop_map(&self) -> &Path {
+        match self {
+            PredictionConfig::GtfsRt { stop_map, .. }
+            | PredictionConfig::NextBus { stop_map, .. }
+            | PredictionConfig::Siri { stop_map, .. } => stop_map,
+        }
+    }
+
+    /// How often to poll, by default every 30 seconds.
+    pub fn interval_secs(&self) -> u64 {
+        match self {
+            PredictionConfig::GtfsRt { interval_secs, .. }
+            | PredictionConfig::NextBus { interval_secs, .. }
+            | PredictionConfig::Siri { interval_secs, .. } => interval_secs.unwrap_or(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClockConfig {
+    pub sync_secs: u64,
+    pub max_drift_secs: u16,
+    pub max_failures: usize,
+    pub history: usize,
+}
+
+impl Default for ClockConfig {
+    fn default() -> Self {
+        ClockConfig {
+            sync_secs: 60,
+            max_drift_secs: 5,
+            max_failures: 3,
+            history: 1440,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FirmwareConfig {
+    pub chunk_bytes: usize,
+    pub batch: usize,
+    pub pace_ms: u64,
+    pub max_retries: usize,
+    pub reboot_timeout_secs: u64,
+}
+
+impl Default for FirmwareConfig {
+    fn default() -> Self {
+        FirmwareConfig {
+            chunk_bytes: 1024,
+            batch: 16,
+            pace_ms: 20,
+            max_retries: 3,
+            reboot_timeout_secs: 300,
+        }
+    }
+}
+
+/// A sign we know about. Connections are matched to it by IP address or, failing that, by MAC
+/// address.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SignConfig {
+    pub id: String,
+    pub ip: Option<IpAddr>,
+    pub mac: Option<String>,
+    pub station: Option<String>,
+    pub platform: Option<String>,
+    #[serde(default)]
+    pub lines: Vec<String>,
+    pub profile: Option<String>,
+    #[serde(default)]
+    pub stops: Vec<Stop>,
+    pub arrivals: Option<Layout>,
+    pub time_zone: Option<Tz>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GroupConfig {
+    pub station: Option<String>,
+    pub platform: Option<String>,
+    pub line: Option<String>,
+    pub signs: Vec<String>,
+    /// A profile for members that don't have their own.
+    pub profile: Option<String>,
+    /// A time zone for members that don't have their own.
+    pub time_zone: Option<Tz>,
+}
+
+impl GroupConfig {
+    fn selects(&self, sign: &SignConfig) -> bool {
+        let has_selector = self.station.is_some() || self.platform.is_some() || self.line.is_some();
+
+        has_selector
+            && self
+                .station
+                .as_ref()
+                .is_none_or(|s| sign.station.as_ref() == Some(s))
+            && self
+                .platform
+                .as_ref()
+                .is_none_or(|p| sign.platform.as_ref() == Some(p))
+            && self.line.as_ref().is_none_or(|l| sign.lines.contains(l))
+    }
+}
+
+impl Config {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let config: Config = toml::from_str(&std::fs::read_to_string(path)?)?;
+        config.check()?;
+        Ok(config)
+    }
+
+    /// Check that every profile and parameter referred to exists.
+    fn check(&self) -> Result<(), ConfigError> {
+        if self.sound_dir.is_some() && self.sound_url.is_none() {
+            return Err(ConfigError::Invalid(
+                "sound_dir needs a sound_url for signs to fetch sounds from".to_string(),
+            ));
+        }
+        if let Some(url) = &self.sound_url
+            && url.trim_end_matches('/').len() + URL_OVERHEAD > u8::MAX.into()
+        {
+            return Err(ConfigError::Invalid(format!(
+                "sound_url can be at most {} bytes, for sounds' URLs to fit in a StopCfg",
+                usize::from(u8::MAX) - URL_OVERHEAD
+            )));
+        }
+        if self.count_interval_secs == Some(0) {
+            return Err(ConfigError::Invalid(
+                "count_interval_secs must be at least 1".to_string(),
+            ));
+        }
+        if self.sound_scan_secs == Some(0) {
+            return Err(ConfigError::Invalid(
+                "sound_scan_secs must be at least 1".to_string(),
+            ));
+        }
+        if self.clock.sync_secs == 0 {
+            return Err(ConfigError::Invalid(
+                "clock sync_secs must be at least 1".to_string(),
+            ));
+        }
+        if !(1..=MAX_CHUNK).contains(&self.firmware.chunk_bytes) {
+            return Err(ConfigError::Invalid(format!(
+                "firmware chunk_bytes must be from 1 to {MAX_CHUNK}"
+            )));
+        }
+        if self.firmware.batch == 0 {
+            return Err(ConfigError::Invalid(
+                "firmware batch must be at least 1".to_string(),
+            ));
+        }
+        if self.firmware.reboot_timeout_secs == 0 {
+            return Err(ConfigError::Invalid(
+                "firmware reboot_timeout_secs must be at least 1".to_string(),
+            ));
+        }
+        if self.predictions.iter().any(|p| p.interval_secs() == 0) {
+            return Err(ConfigError::Invalid(
+                "predictions interval_secs must be at least 1".to_string(),
+            ));
+        }
+
+        for (name, values) in &self.profiles {
+            if let Some(param) = values.keys().find(|p| !self.params.contains_key(*p)) {
+                return Err(ConfigError::Invalid(format!(
+                    "profile {name} sets unknown parameter {param}"
+                )));
+            }
+        }
+
+        for sign in &self.signs {
+            for stop in &sign.stops {
+                stop.check()
+                    .map_err(|e| ConfigError::Invalid(format!("sign {}: {e}", sign.id)))?;
+            }
+            stops::check_ids(&sign.stops)
+                .map_err(|e| ConfigError::Invalid(format!("sign {}: {e}", sign.id)))?;
+        }
+
+        let assigned = self
+            .signs
+            .iter()
+            .map(|s| (&s.id, &s.profile))
+            .chain(self.groups.iter().map(|(name, g)| (name, &g.profile)));
+        for (owner, profile) in assigned {
+            if let Some(profile) = profile
+                && !self.profiles.contains_key(profile)
+            {
+                return Err(ConfigError::Invalid(format!(
+                    "{owner} has unknown profile {profile}"
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// A parameter's number, given its name or the number itself.
+    pub fn param(&self, name: &str) -> Option<u8> {
+        self.params.get(name).copied().or_else(|| name.parse().ok())
+    }
+
+    pub fn param_name(&self, param: u8) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(_, p)| **p == param)
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// A profile's parameter values, by parameter number.
+    pub fn profile(&self, name: &str) -> Option<BTreeMap<u8, u8>> {
+        let values = self.profiles.get(name)?;
+        Some(
+            values
+                .iter()
+                .filter_map(|(param, value)| Some((*self.params.get(param)?, *value)))
+                .collect(),
+        )
+    }
+
+    /// The profile configured for a sign: its own, or else that of the first group it's in that
+    /// has one.
+    pub fn assigned_profile(&self, sign: &str) -> Option<&str> {
+        let own = self.signs.iter().find(|s| s.id == sign);
+        if let Some(profile) = own.and_then(|s| s.profile.as_deref()) {
+            return Some(profile);
+        }
+
+        self.first_group(sign, |g| g.profile.is_some())
+            .and_then(|g| g.profile.as_deref())
+    }
+
+    /// The time zone a sign is in: its own, or else that of the first group it's in that has
+    /// one, or else the server-wide one.
+    pub fn time_zone(&self, sign: &str) -> Option<Tz> {
+        let own = self.signs.iter().find(|s| s.id == sign);
+        own.and_then(|s| s.time_zone)
+            .or_else(|| self.first_group(sign, |g| g.time_zone.is_some())?.time_zone)
+            .or(self.time_zone)
+    }
+
+    /// The first group a sign is in that has some setting.
+    fn first_group(&self, sign: &str, has: impl Fn(&GroupConfig) -> bool) -> Option<&GroupConfig> {
+        self.groups
+            .iter()
+            .filter(|(_, g)| has(g))
+            .find(|(name, _)| {
+                self.group_members(name)
+                    .is_some_and(|m| m.iter().any(|s| s == sign))
+            })
+            .map(|(_, g)| g)
+    }
+
+    /// How arrivals are laid out on a sign.
+    pub fn arrivals(&self, sign: &str) -> &Layout {
+        self.signs
+            .iter()
+            .find(|s| s.id == sign)
+            .and_then(|s| s.arrivals.as_ref())
+            .unwrap_or(&self.arrivals)
+    }
+
+    /// The ids of the signs in a group, or `None` if there's no such group.
+    pub fn group_members(&self, name: &str) -> Option<Vec<String>> {
+        let group = self.groups.get(name)?;
+
+        let mut members: Vec<String> = self
+            .signs
+            .iter()
+            .filter(|s| group.selects(s))
+            .map(|s| s.id.clone())
+            .collect();
+        for id in &group.signs {
+            if !members.contains(id) {
+                members.push(id.clone());
+            }
+        }
+
+        Some(members)
+    }
+}
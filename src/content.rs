@@ -0,0 +1,263 @@
+//! Content items, as set on signs with `ContentMsg`, and their lifecycle.
+//!
+//! Each sign holds any number of items, told apart by content ids that are allocated per sign.
+//! Changing an item sends it again under the same id, replacing it in place on the sign, and
+//! withdrawing it deletes it from the sign with `ContentDelete`. Every change is made to the
+//! sign's desired state first, so signs that are offline catch up when they reconnect.
+
+use serde::{Deserialize, Serialize};
+
+use crate::hub::{Delivery, Outcome};
+use crate::msg::Message;
+use crate::msg::content::PayloadType;
+use crate::msg::content_schedule::Schedule;
+use crate::registry::SignId;
+use crate::server::Server;
+use crate::store::schedule_message;
+
+/// The most bytes a `ContentMsg` frame has room for after its header and checksum.
+const MAX_MSG_LEN: usize = u16::MAX as usize - 5;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Content {
+    pub content_id: u16,
+    pub content_channel: u8,
+    pub count_impressions: bool,
+    pub display_indefinitely: bool,
+    pub booking_id: u16,
+    pub priority: u16,
+    pub payloads: Vec<(PayloadType, Vec<u8>)>,
+    /// A sound from the library, which the `SoundURL` and `SoundChecksum` payloads are filled in
+    /// from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sound: Option<String>,
+}
+
+impl Content {
+    /// Plain text, displayed until further notice.
+    pub fn text(content_id: u16, text: &str) -> Self {
+        Content {
+            content_id,
+            content_channel: 2,
+            count_impressions: false,
+            display_indefinitely: true,
+            booking_id: 0,
+            priority: 0,
+            payloads: vec![(PayloadType::Msg, text.as_bytes().to_vec())],
+            sound: None,
+        }
+    }
+
+    /// Check that the item fits in a `ContentMsg`.
+    pub fn check(&self) -> Result<(), String> {
+        if self.payloads.len() > u8::MAX.into() {
+            return Err(format!("Content can have at most {} payloads.", u8::MAX));
+        }
+        let len = 9 + self
+            .payloads
+            .iter()
+            .map(|(_, p)| 3 + p.len())
+            .sum::<usize>();
+        if len > MAX_MSG_LEN {
+            return Err(format!(
+                "Content is {len} bytes, but a message has room for {MAX_MSG_LEN}."
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn to_message(&self) -> Message {
+        Message::ContentMsg {
+            content_id: self.content_id,
+            content_channel: self.content_channel,
+            count_impressions: self.count_impressions,
+            display_indefinitely: self.display_indefinitely,
+            booking_id: self.booking_id,
+            priority: self.priority,
+            payloads: self.payloads.clone(),
+        }
+    }
+}
+
+/// The content id an item was added under, and how sending it went.
+pub type Added = (u16, Vec<Delivery>);
+
+/// Add a new item to a sign under a freshly allocated content id, which `make` is given. `None` if
+/// the sign has no content ids left.
+pub fn create(server: &Server, sign: &str, make: impl FnOnce(u16) -> Content) -> Option<Added> {
+    let content = add(server, sign, make)?;
+    Some((content.content_id, send(server, sign, &content)))
+}
+
+/// Set the item a sign knows by `name`, creating it if there isn't one yet.
+pub fn set_named(
+    server: &Server,
+    sign: &str,
+    name: &str,
+    make: impl FnOnce(u16) -> Content,
+) -> Option<Added> {
+    let content = add_named(server, sign, name, make)?;
+    Some((content.content_id, send(server, sign, &content)))
+}
+
+/// `create` on each of several signs. Every sign's item is stored before any is sent, and they
+/// are all sent together, so the signs are waited on at once rather than one after another.
+pub fn create_each(
+    server: &Server,
+    signs: &[SignId],
+    make: impl Fn(u16) -> Content,
+) -> Vec<(SignId, Option<Added>)> {
+    let added = signs
+        .iter()
+        .map(|sign| (sign.clone(), add(server, sign, &make)))
+        .collect();
+    send_each(server, added)
+}
+
+/// `set_named` on each of several signs, sent together as `create_each` does.
+pub fn set_named_each(
+    server: &Server,
+    signs: &[SignId],
+    name: &str,
+    make: impl Fn(u16) -> Content,
+) -> Vec<(SignId, Option<Added>)> {
+    let added = signs
+        .iter()
+        .map(|sign| (sign.clone(), add_named(server, sign, name, &make)))
+        .collect();
+    send_each(server, added)
+}
+
+fn add(server: &Server, sign: &str, make: impl FnOnce(u16) -> Content) -> Option<Content> {
+    server.store.update(sign, |state| {
+        let content = make(state.free_content_id()?);
+        state.content.insert(content.content_id, content.clone());
+        Some(content)
+    })
+}
+
+fn add_named(
+    server: &Server,
+    sign: &str,
+    name: &str,
+    make: impl FnOnce(u16) -> Content,
+) -> Option<Content> {
+    server.store.update(sign, |state| {
+        let id = match state.names.get(name) {
+            Some(id) if state.content.contains_key(id) => *id,
+            _ => state.free_content_id()?,
+        };
+        let content = make(id);
+
+        state.names.insert(name.to_string(), id);
+        state.content.insert(id, content.clone());
+        Some(content)
+    })
+}
+
+/// Replace an existing item in place. `None` if the sign has no item with that content id.
+pub fn replace(server: &Server, sign: &str, content: Content) -> Option<Vec<Delivery>> {
+    server.store.update(sign, |state| {
+        let item = state.content.get_mut(&content.content_id)?;
+        *item = content.clone();
+        Some(())
+    })?;
+
+    Some(send(server, sign, &content))
+}
+
+/// Take an item off a sign. `None` if the sign has no item with that content id.
+pub fn withdraw(server: &Server, sign: &str, content_id: u16) -> Option<Vec<Delivery>> {
+    server.store.update(sign, |state| {
+        state.content.remove(&content_id)?;
+        state.schedules.remove(&content_id);
+        state.names.retain(|_, id| *id != content_id);
+        state.withdrawn.insert(content_id);
+        Some(())
+    })?;
+
+    let deliveries = server
+        .hub
+        .send_to(&[sign.to_string()], &Message::ContentDelete { content_id });
+    for delivery in &deliveries {
+        if let Outcome::Acked { error } = delivery.outcome {
+            confirm_withdrawn(server, sign, content_id, error);
+        }
+    }
+
+    Some(deliveries)
+}
+
+/// Set when an item is shown, replacing any schedule it had. A `None` schedule shows it
+/// indefinitely. `None` if the sign has no item with that content id.
+pub fn schedule(
+    server: &Server,
+    sign: &str,
+    content_id: u16,
+    schedule: Option<Vec<Schedule>>,
+) -> Option<Vec<Delivery>> {
+    server.store.update(sign, |state| {
+        state.content.contains_key(&content_id).then(|| {
+            state.schedules.insert(content_id, schedule.clone());
+        })
+    })?;
+
+    Some(
+        server
+            .hub
+            .send_to(&[sign.to_string()], &schedule_message(content_id, schedule)),
+    )
+}
+
+/// Drop an item's schedule, so it's shown indefinitely again. `None` if the item has no schedule.
+pub fn unschedule(server: &Server, sign: &str, content_id: u16) -> Option<Vec<Delivery>> {
+    server
+        .store
+        .update(sign, |state| state.schedules.remove(&content_id))?;
+
+    Some(
+        server
+            .hub
+            .send_to(&[sign.to_string()], &schedule_message(content_id, None)),
+    )
+}
+
+/// Forget about a withdrawn item once the sign has acknowledged deleting it. An error means the
+/// sign couldn't delete it, most likely because it didn't have it, so there's no use retrying.
+pub fn confirm_withdrawn(server: &Server, sign: &str, content_id: u16, error: u8) {
+    if error != 0 {
+        log::warn!("{sign} failed to delete content {content_id} with error {error}.");
+    }
+    server
+        .store
+        .update(sign, |state| state.withdrawn.remove(&content_id));
+}
+
+fn send(server: &Server, sign: &str, content: &Content) -> Vec<Delivery> {
+    server
+        .hub
+        .send_to(&[sign.to_string()], &content.to_message())
+}
+
+/// Send every sign its added item in one go, and hand each sign back its own deliveries.
+fn send_each(
+    server: &Server,
+    added: Vec<(SignId, Option<Content>)>,
+) -> Vec<(SignId, Option<Added>)> {
+    let msgs: Vec<_> = added
+        .iter()
+        .filter_map(|(sign, content)| Some((sign.clone(), content.as_ref()?.to_message())))
+        .collect();
+    let mut deliveries = server.hub.send_each(&msgs);
+
+    added
+        .into_iter()
+        .map(|(sign, content)| {
+            let placed = content.map(|content| {
+                let mine = deliveries.extract_if(.., |d| d.sign == sign).collect();
+                (content.content_id, mine)
+            });
+            (sign, placed)
+        })
+        .collect()
+}
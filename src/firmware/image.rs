@@ -0,0 +1,497 @@
+//! Firmware images, and the files they come in.
+//!
+//! An image is the code to write at each of a few address ranges. It can be read from Intel HEX
+//! (`.hex`), Motorola S-records (`.s19`, `.s28`, `.s37`), or raw binary written from a base
+//! address. Every record's checksum is checked, and so is that no two records write the same
+//! address and that nothing is written above 0xffff, the top of the sign's address space.
+//!
+//! An image's manifest gives its segments, its size and a CRC-32 of its code and where it goes, for
+//! operators to check against what they meant to send before sending it.
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use thiserror::Error;
+
+/// The end of the address space firmware is written into.
+const ADDRESS_SPACE: u32 = 0x10000;
+
+#[derive(Error, Debug)]
+pub enum ImageError {
+    #[error("Image is empty.")]
+    Empty,
+    #[error("Image isn't text.")]
+    NotText,
+    #[error("Line {line}: {reason}")]
+    Syntax { line: usize, reason: String },
+    #[error("Line {line}: checksum is {found:#04x}, should be {expected:#04x}.")]
+    Checksum {
+        line: usize,
+        expected: u8,
+        found: u8,
+    },
+    #[error("{len} bytes at {addr:#06x} run past the end of the address space.")]
+    TooLong { addr: u32, len: usize },
+    #[error("Data at {0:#06x} is written more than once.")]
+    Overlap(u32),
+    #[error("No end-of-file record.")]
+    NoEnd,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    IntelHex,
+    Srec,
+    Binary,
+}
+
+impl Format {
+    /// A format by name or file extension: `hex`, `srec` (or `s19`, `s28`, `s37`) or `bin`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "hex" | "ihex" => Some(Format::IntelHex),
+            "srec" | "s19" | "s28" | "s37" => Some(Format::Srec),
+            "bin" => Some(Format::Binary),
+            _ => None,
+        }
+    }
+}
+
+/// Code to write from an address up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub addr: u16,
+    pub data: Vec<u8>,
+}
+
+/// Segments in address order, none of them overlapping or touching.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Image {
+    pub segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Manifest {
+    /// Bytes of code, not counting the gaps between segments.
+    pub size: usize,
+    /// CRC-32 of each segment's address and length (big-endian, 16 and 32 bits) followed by its
+    /// code, one segment after another, in hex.
+    pub crc32: String,
+    pub segments: Vec<SegmentInfo>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SegmentInfo {
+    pub addr: u16,
+    pub len: usize,
+}
+
+impl Image {
+    /// Read an image in the given format. `base` is where raw binary is written from.
+    pub fn parse(format: Format, bytes: Vec<u8>, base: u16) -> Result<Self, ImageError> {
+        match format {
+            Format::IntelHex => Image::intel_hex(text(&bytes)?),
+            Format::Srec => Image::srec(text(&bytes)?),
+            Format::Binary => Image::raw(base, bytes),
+        }
+    }
+
+    /// A raw binary image, written from `base` up.
+    pub fn raw(base: u16, data: Vec<u8>) -> Result<Self, ImageError> {
+        let mut records = Records::default();
+        records.add(base.into(), data)?;
+        records.image()
+    }
+
+    /// Intel HEX, with 16-bit addresses or extended segment or linear addresses.
+    pub fn intel_hex(text: &str) -> Result<Self, ImageError> {
+        let mut records = Records::default();
+        // What extended address records add to the addresses of the data records after them.
+        let mut offset = 0;
+
+        for (line, record) in lines(text) {
+            let Some(hex) = record.strip_prefix(':') else {
+                return Err(syntax(line, "doesn't start with ':'"));
+            };
+            let bytes = decode(line, hex)?;
+            if bytes.len() < 5 || bytes.len() != usize::from(bytes[0]) + 5 {
+                return Err(syntax(line, "length doesn't match the record"));
+            }
+
+            let (body, checksum) = bytes.split_at(bytes.len() - 1);
+            let expected = body
+                .iter()
+                .fold(0u8, |sum, b| sum.wrapping_add(*b))
+                .wrapping_neg();
+            if checksum[0] != expected {
+                return Err(ImageError::Checksum {
+                    line,
+                    expected,
+                    found: checksum[0],
+                });
+            }
+
+            let addr = u32::from(u16::from_be_bytes([body[1], body[2]]));
+            let data = &body[4..];
+            match body[3] {
+                0x00 => records.add(offset + addr, data.to_vec())?,
+                0x01 => return records.image(),
+                kind @ (0x02 | 0x04) => {
+                    let [high, low] = data else {
+                        return Err(syntax(line, "extended address isn't two bytes"));
+                    };
+                    let shift = if kind == 0x02 { 4 } else { 16 };
+                    offset = u32::from(u16::from_be_bytes([*high, *low])) << shift;
+                }
+                // Where to start running, which is the sign's business.
+                0x03 | 0x05 => {}
+                kind => return Err(syntax(line, &format!("bad record type {kind:02x}"))),
+            }
+        }
+
+        Err(ImageError::NoEnd)
+    }
+
+    /// Motorola S-records, with 16, 24 or 32-bit addresses.
+    pub fn srec(text: &str) -> Result<Self, ImageError> {
+        let mut records = Records::default();
+        let mut data_records = 0;
+
+        for (line, record) in lines(text) {
+            let mut chars = record.chars();
+            let (Some('S'), Some(kind)) = (chars.next(), chars.next()) else {
+                return Err(syntax(line, "doesn't start with 'S'"));
+            };
+            let bytes = decode(line, chars.as_str())?;
+            if bytes.is_empty() || bytes.len() != usize::from(bytes[0]) + 1 {
+                return Err(syntax(line, "length doesn't match the record"));
+            }
+
+            let (body, checksum) = bytes.split_at(bytes.len() - 1);
+            let expected = !body.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+            if checksum[0] != expected {
+                return Err(ImageError::Checksum {
+                    line,
+                    expected,
+                    found: checksum[0],
+                });
+            }
+
+            let addr_len = match kind {
+                '0' | '1' | '5' | '9' => 2,
+                '2' | '6' | '8' => 3,
+                '3' | '7' => 4,
+                kind => return Err(syntax(line, &format!("bad record type S{kind}"))),
+            };
+            if body.len() < 1 + addr_len {
+                return Err(syntax(line, "too short for its address"));
+            }
+            let addr = body[1..=addr_len]
+                .iter()
+                .fold(0u32, |addr, b| addr << 8 | u32::from(*b));
+            let data = &body[1 + addr_len..];
+
+            match kind {
+                '1' | '2' | '3' => {
+                    records.add(addr, data.to_vec())?;
+                    data_records += 1;
+                }
+                '5' | '6' if addr != data_records => {
+                    return Err(syntax(
+                        line,
+                        &format!("counts {addr} data records, but there are {data_records}"),
+                    ));
+                }
+                '7' | '8' | '9' => return records.image(),
+                // A header, or a count that's right.
+                _ => {}
+            }
+        }
+
+        records.image()
+    }
+
+    /// How many bytes of code there are.
+    pub fn size(&self) -> usize {
+        self.segments.iter().map(|s| s.data.len()).sum()
+    }
+
+    pub fn manifest(&self) -> Manifest {
+        let mut crc = crc32fast::Hasher::new();
+        for segment in &self.segments {
+            crc.update(&segment.addr.to_be_bytes());
+            crc.update(&(segment.data.len() as u32).to_be_bytes());
+            crc.update(&segment.data);
+        }
+
+        Manifest {
+            size: self.size(),
+            crc32: format!("{:08x}", crc.finalize()),
+            segments: self
+                .segments
+                .iter()
+                .map(|s| SegmentInfo {
+                    addr: s.addr,
+                    len: s.data.len(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Data records by address, checked as they're added.
+#[derive(Default)]
+struct Records(BTreeMap<u32, Vec<u8>>);
+
+impl Records {
+    fn add(&mut self, addr: u32, data: Vec<u8>) -> Result<(), ImageError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let Some(end) = u32::try_from(data.len())
+            .ok()
+            .and_then(|len| addr.checked_add(len))
+            .filter(|end| *end <= ADDRESS_SPACE)
+        else {
+            return Err(ImageError::TooLong {
+                addr,
+                len: data.len(),
+            });
+        };
+
+        // Records are kept apart, so only the nearest on either side can overlap.
+        let before = self.0.range(..=addr).next_back();
+        if let Some((start, data)) = before
+            && start + data.len() as u32 > addr
+        {
+            return Err(ImageError::Overlap(addr));
+        }
+        if let Some((start, _)) = self.0.range(addr + 1..).next()
+            && *start < end
+        {
+            return Err(ImageError::Overlap(*start));
+        }
+
+        self.0.insert(addr, data);
+        Ok(())
+    }
+
+    /// Join up records that run on from each other.
+    fn image(self) -> Result<Image, ImageError> {
+        let mut segments: Vec<Segment> = Vec::new();
+        for (addr, data) in self.0 {
+            match segments.last_mut() {
+                Some(last) if u32::from(last.addr) + last.data.len() as u32 == addr => {
+                    last.data.extend(data)
+                }
+                _ => segments.push(Segment {
+                    addr: addr as u16,
+                    data,
+                }),
+            }
+        }
+
+        match segments.is_empty() {
+            true => Err(ImageError::Empty),
+            false => Ok(Image { segments }),
+        }
+    }
+}
+
+fn text(bytes: &[u8]) -> Result<&str, ImageError> {
+    std::str::from_utf8(bytes).map_err(|_| ImageError::NotText)
+}
+
+/// Non-blank lines, numbered from 1.
+fn lines(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty())
+}
+
+fn decode(line: usize, hex: &str) -> Result<Vec<u8>, ImageError> {
+    hex::decode(hex).map_err(|e| syntax(line, &e.to_string()))
+}
+
+fn syntax(line: usize, reason: &str) -> ImageError {
+    ImageError::Syntax {
+        line,
+        reason: reason.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An Intel HEX record, with its checksum worked out.
+    fn ihex(kind: u8, addr: u16, data: &[u8]) -> String {
+        let mut bytes = vec![data.len() as u8];
+        bytes.extend(addr.to_be_bytes());
+        bytes.push(kind);
+        bytes.extend(data);
+        let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        bytes.push(sum.wrapping_neg());
+        format!(":{}", hex::encode_upper(bytes))
+    }
+
+    /// An S-record, with its checksum worked out.
+    fn srec(kind: char, addr: &[u8], data: &[u8]) -> String {
+        let mut bytes = vec![(addr.len() + data.len() + 1) as u8];
+        bytes.extend(addr);
+        bytes.extend(data);
+        let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        bytes.push(!sum);
+        format!("S{kind}{}", hex::encode_upper(bytes))
+    }
+
+    const EOF: &str = ":00000001FF";
+
+    fn file(records: &[String]) -> String {
+        records.join("\n")
+    }
+
+    #[test]
+    fn intel_hex_checksum() {
+        let good = ihex(0x00, 0x0100, &[1, 2, 3]);
+        assert!(Image::intel_hex(&file(&[good.clone(), EOF.into()])).is_ok());
+
+        let bad = format!("{}00", &good[..good.len() - 2]);
+        assert!(matches!(
+            Image::intel_hex(&file(&[bad, EOF.into()])),
+            Err(ImageError::Checksum { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn srec_checksum() {
+        let mut bad = srec('1', &[0x01, 0x00], &[1, 2, 3]);
+        bad.replace_range(bad.len() - 2.., "00");
+        assert!(matches!(
+            Image::srec(&bad),
+            Err(ImageError::Checksum { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn overlapping_records() {
+        let text = file(&[
+            ihex(0x00, 0x0100, &[0; 16]),
+            ihex(0x00, 0x010f, &[0; 4]),
+            EOF.into(),
+        ]);
+        assert!(matches!(
+            Image::intel_hex(&text),
+            Err(ImageError::Overlap(0x010f))
+        ));
+
+        // Overlapping the record after it, too.
+        let text = file(&[
+            ihex(0x00, 0x0110, &[0; 4]),
+            ihex(0x00, 0x0100, &[0; 17]),
+            EOF.into(),
+        ]);
+        assert!(matches!(
+            Image::intel_hex(&text),
+            Err(ImageError::Overlap(0x0110))
+        ));
+    }
+
+    #[test]
+    fn extended_segment_address() {
+        let text = file(&[
+            ihex(0x02, 0, &[0x01, 0x00]),
+            ihex(0x00, 0x0010, &[1, 2]),
+            EOF.into(),
+        ]);
+        let image = Image::intel_hex(&text).unwrap();
+        assert_eq!(
+            image.segments,
+            [Segment {
+                addr: 0x1010,
+                data: vec![1, 2]
+            }]
+        );
+    }
+
+    #[test]
+    fn extended_linear_address() {
+        let text = file(&[
+            ihex(0x04, 0, &[0x00, 0x00]),
+            ihex(0x00, 0x2000, &[1]),
+            EOF.into(),
+        ]);
+        assert_eq!(Image::intel_hex(&text).unwrap().segments[0].addr, 0x2000);
+
+        // Anything above 64K is past the end of the sign's address space.
+        let text = file(&[
+            ihex(0x04, 0, &[0x00, 0x01]),
+            ihex(0x00, 0x0000, &[1]),
+            EOF.into(),
+        ]);
+        assert!(matches!(
+            Image::intel_hex(&text),
+            Err(ImageError::TooLong { addr: 0x10000, .. })
+        ));
+    }
+
+    #[test]
+    fn srec_count_mismatch() {
+        let data = [
+            srec('1', &[0x00, 0x00], &[1, 2]),
+            srec('1', &[0x00, 0x02], &[3, 4]),
+        ];
+        let counted = |n: u16| {
+            let mut records = data.to_vec();
+            records.push(srec('5', &n.to_be_bytes(), &[]));
+            records.push(srec('9', &[0, 0], &[]));
+            Image::srec(&file(&records))
+        };
+
+        assert!(counted(2).is_ok());
+        assert!(matches!(
+            counted(3),
+            Err(ImageError::Syntax { line: 3, .. })
+        ));
+    }
+
+    #[test]
+    fn intel_hex_needs_eof() {
+        let text = ihex(0x00, 0x0000, &[1, 2, 3]);
+        assert!(matches!(Image::intel_hex(&text), Err(ImageError::NoEnd)));
+    }
+
+    #[test]
+    fn adjacent_records_are_joined() {
+        let text = file(&[
+            srec('0', &[0, 0], b"hdr"),
+            srec('1', &[0x10, 0x04], &[5, 6]),
+            srec('1', &[0x10, 0x00], &[1, 2, 3, 4]),
+            srec('1', &[0x20, 0x00], &[9]),
+            srec('9', &[0, 0], &[]),
+        ]);
+        let image = Image::srec(&text).unwrap();
+        assert_eq!(
+            image.segments,
+            [
+                Segment {
+                    addr: 0x1000,
+                    data: vec![1, 2, 3, 4, 5, 6]
+                },
+                Segment {
+                    addr: 0x2000,
+                    data: vec![9]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn manifest_crc_covers_addresses() {
+        let a = Image::raw(0x1000, vec![1, 2, 3]).unwrap().manifest();
+        let b = Image::raw(0x2000, vec![1, 2, 3]).unwrap().manifest();
+        assert_eq!(a.size, b.size);
+        assert_ne!(a.crc32, b.crc32);
+    }
+}
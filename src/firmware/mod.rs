@@ -0,0 +1,485 @@
+//! Firmware updates, streamed to signs as `FirmwareCode` chunks.
+//!
+//! An image is split into chunks of at most `chunk_bytes`, each giving the address it's written at
+//! and a sequence number that rolls over from 255 to 0. Chunks are sent a batch at a time, paced
+//! by `pace_ms`. Signs don't acknowledge `FirmwareCode`, so each batch is followed by a
+//! `GetCfgParam`, which they do: signs handle messages in order, so its `AckGetCfgParam` confirms
+//! every chunk before it. A batch that goes unconfirmed is sent again, up to `max_retries` times
+//! in a row.
+//!
+//! Once every chunk is confirmed the sign is told to reboot, and the update has succeeded when it
+//! comes back with `AppRunning` giving `NewFirmware` as the reason, or failed if it hasn't within
+//! `reboot_timeout_secs`. If the sign disconnects part way through, the update stops, and picks up
+//! from the last confirmed chunk when it reconnects.
+
+pub mod image;
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Serialize;
+use thiserror::Error;
+
+use self::image::{Image, ImageError};
+use crate::config::FirmwareConfig;
+use crate::hub::{Hub, Outcome};
+use crate::msg::Message;
+use crate::msg::app_running::AppRunningReason;
+use crate::registry::SignId;
+use crate::server::Server;
+
+/// The parameter read to confirm chunks. Any will do, as only the acknowledgement matters.
+const CONFIRM_PARAM: u8 = 0;
+
+/// The most code a `FirmwareCode` frame has room for after its header, address, length and
+/// checksum.
+pub const MAX_CHUNK: usize = u16::MAX as usize - 10;
+
+#[derive(Error, Debug)]
+pub enum FirmwareError {
+    #[error("Invalid image: {0}")]
+    Image(#[from] ImageError),
+    #[error("{0} is already being updated.")]
+    Busy(SignId),
+}
+
+impl Image {
+    /// The image as `FirmwareCode` messages, in the order they're sent.
+    fn chunks(&self, max: usize) -> Vec<Message> {
+        let mut chunks = Vec::new();
+        for segment in &self.segments {
+            for (i, code) in segment.data.chunks(max).enumerate() {
+                chunks.push(Message::FirmwareCode {
+                    seq: chunks.len() as u8,
+                    dest_addr: (usize::from(segment.addr) + i * max) as u16,
+                    num_bytes: code.len() as u16,
+                    code_chunk: code.to_vec(),
+                });
+            }
+        }
+        chunks
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    Sending,
+    /// The sign disconnected. Sending carries on when it reconnects.
+    Interrupted,
+    /// Every chunk is confirmed, and the sign has been told to reboot into the new firmware.
+    Rebooting,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Progress {
+    pub sign: SignId,
+    pub state: State,
+    pub bytes: usize,
+    /// As in the image's manifest.
+    pub crc32: String,
+    pub chunks: usize,
+    /// How many chunks the sign is known to have.
+    pub confirmed: usize,
+    pub started: DateTime<Utc>,
+    pub updated: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+struct Update {
+    chunks: Arc<Vec<Message>>,
+    progress: Progress,
+    /// When a rebooting sign has to be back with its new firmware by.
+    deadline: Option<DateTime<Utc>>,
+}
+
+impl Update {
+    fn set(&mut self, state: State, error: Option<String>) {
+        self.progress.state = state;
+        self.progress.error = error;
+        self.progress.updated = Utc::now();
+    }
+
+    /// Fail the update if the sign is rebooting and has run out of time.
+    fn expire(&mut self, now: DateTime<Utc>) {
+        if self.progress.state == State::Rebooting && self.deadline.is_some_and(|d| d <= now) {
+            log::warn!(
+                "{} didn't come back from its firmware update in time.",
+                self.progress.sign
+            );
+            self.set(
+                State::Failed,
+                Some("didn't come back with its new firmware in time".to_string()),
+            );
+        }
+    }
+}
+
+/// The latest update of each sign that's had one.
+pub struct Firmware {
+    config: FirmwareConfig,
+    updates: Mutex<BTreeMap<SignId, Update>>,
+}
+
+impl Firmware {
+    pub fn new(config: &FirmwareConfig) -> Self {
+        Firmware {
+            config: config.clone(),
+            updates: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn progress(&self, sign: &str) -> Option<Progress> {
+        self.xs().get(sign).map(|u| u.progress.clone())
+    }
+
+    pub fn list(&self) -> Vec<Progress> {
+        self.xs().values().map(|u| u.progress.clone()).collect()
+    }
+
+    /// Stop updating a sign. `None` if it's never been updated.
+    pub fn cancel(&self, sign: &str) -> Option<Progress> {
+        let mut updates = self.xs();
+        let update = updates.get_mut(sign)?;
+        if matches!(
+            update.progress.state,
+            State::Sending | State::Interrupted | State::Rebooting
+        ) {
+            log::info!("Cancelled {sign}'s firmware update.");
+            update.set(State::Cancelled, None);
+        }
+        Some(update.progress.clone())
+    }
+
+    /// See whether a rebooting sign has come back with its new firmware.
+    pub fn observe(&self, sign: &str, msg: &Message) {
+        let Message::AppRunning { reason, .. } = msg else {
+            return;
+        };
+        let mut updates = self.xs();
+        let Some(update) = updates
+            .get_mut(sign)
+            .filter(|u| u.progress.state == State::Rebooting)
+        else {
+            return;
+        };
+
+        match reason {
+            AppRunningReason::NewFirmware => {
+                log::info!("{sign} is running its new firmware.");
+                update.set(State::Succeeded, None);
+            }
+            reason => {
+                log::warn!("{sign} came back from a firmware update with {reason:?}.");
+                update.set(State::Failed, Some(format!("came back with {reason:?}")));
+            }
+        }
+    }
+
+    fn begin(
+        &self,
+        sign: &str,
+        image: &Image,
+    ) -> Result<(Progress, Arc<Vec<Message>>), FirmwareError> {
+        if image.size() == 0 {
+            return Err(ImageError::Empty.into());
+        }
+
+        let mut updates = self.xs();
+        if updates.get(sign).is_some_and(|u| {
+            matches!(
+                u.progress.state,
+                State::Sending | State::Interrupted | State::Rebooting
+            )
+        }) {
+            return Err(FirmwareError::Busy(sign.to_string()));
+        }
+
+        let chunks = Arc::new(image.chunks(self.config.chunk_bytes));
+        let now = Utc::now();
+        let progress = Progress {
+            sign: sign.to_string(),
+            state: State::Sending,
+            bytes: image.size(),
+            crc32: image.manifest().crc32,
+            chunks: chunks.len(),
+            confirmed: 0,
+            started: now,
+            updated: now,
+            error: None,
+        };
+        updates.insert(
+            sign.to_string(),
+            Update {
+                chunks: chunks.clone(),
+                progress: progress.clone(),
+                deadline: None,
+            },
+        );
+        Ok((progress, chunks))
+    }
+
+    /// Take up an interrupted update. Its chunks, if there is one.
+    fn claim(&self, sign: &str) -> Option<Arc<Vec<Message>>> {
+        let mut updates = self.xs();
+        let update = updates
+            .get_mut(sign)
+            .filter(|u| u.progress.state == State::Interrupted)?;
+        update.set(State::Sending, None);
+        Some(update.chunks.clone())
+    }
+
+    /// How many chunks are confirmed, or `None` if the update has stopped.
+    fn confirmed(&self, sign: &str) -> Option<usize> {
+        self.xs()
+            .get(sign)
+            .filter(|u| u.progress.state == State::Sending)
+            .map(|u| u.progress.confirmed)
+    }
+
+    fn confirm(&self, sign: &str, confirmed: usize) {
+        if let Some(update) = self.xs().get_mut(sign) {
+            update.progress.confirmed = confirmed;
+            update.progress.updated = Utc::now();
+        }
+    }
+
+    /// Move a sending update on to another state.
+    fn end(&self, sign: &str, state: State, error: Option<String>) {
+        if let Some(update) = self
+            .xs()
+            .get_mut(sign)
+            .filter(|u| u.progress.state == State::Sending)
+        {
+            update.set(state, error);
+            if state == State::Rebooting {
+                let timeout = TimeDelta::seconds(self.config.reboot_timeout_secs as i64);
+                update.deadline = Some(update.progress.updated + timeout);
+            }
+        }
+    }
+
+    /// The sign's connection went away. Whether to carry on, as it's connected again.
+    fn interrupt(&self, sign: &str, hub: &Hub) -> bool {
+        let mut updates = self.xs();
+        let Some(update) = updates
+            .get_mut(sign)
+            .filter(|u| u.progress.state == State::Sending)
+        else {
+            return false;
+        };
+
+        // Checked while holding the lock, so that if the sign reconnects after this, resuming
+        // will find the update interrupted.
+        if hub.connection(sign).is_some() {
+            return true;
+        }
+        log::warn!(
+            "{sign} disconnected during its firmware update, with {} of {} chunks confirmed.",
+            update.progress.confirmed,
+            update.progress.chunks
+        );
+        update.set(State::Interrupted, None);
+        false
+    }
+
+    /// The updates, with any whose sign is late back from rebooting failed.
+    fn xs(&self) -> MutexGuard<'_, BTreeMap<SignId, Update>> {
+        let mut updates = self.updates.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Utc::now();
+        updates.values_mut().for_each(|u| u.expire(now));
+        updates
+    }
+}
+
+/// Start updating a sign's firmware in the background.
+pub fn start(server: &Arc<Server>, sign: &str, image: &Image) -> Result<Progress, FirmwareError> {
+    let (progress, chunks) = server.firmware.begin(sign, image)?;
+    log::info!(
+        "Updating {sign}'s firmware: {} bytes in {} chunks.",
+        progress.bytes,
+        progress.chunks
+    );
+
+    let (sv, sign) = (server.clone(), sign.to_string());
+    thread::spawn(move || send(&sv, &sign, &chunks));
+    Ok(progress)
+}
+
+/// Carry on with a sign's interrupted update, if it has one, now that it's reconnected.
+pub fn resume(server: &Server, sign: &str) {
+    if let Some(chunks) = server.firmware.claim(sign) {
+        log::info!("Resuming {sign}'s firmware update.");
+        send(server, sign, &chunks);
+    }
+}
+
+/// Send a sign whatever chunks it doesn't have yet, then tell it to reboot.
+fn send(server: &Server, sign: &str, chunks: &[Message]) {
+    let firmware = &server.firmware;
+    let config = &firmware.config;
+    let pace = Duration::from_millis(config.pace_ms);
+    let mut retries = 0;
+
+    while let Some(confirmed) = firmware.confirmed(sign) {
+        let Some(conn) = server.hub.connection(sign) else {
+            if firmware.interrupt(sign, &server.hub) {
+                continue;
+            }
+            return;
+        };
+
+        if confirmed == chunks.len() {
+            if server.hub.post_to(conn, &Message::Reboot) {
+                log::info!("Sent {sign} its new firmware; rebooting it.");
+                firmware.end(sign, State::Rebooting, None);
+            } else if firmware.interrupt(sign, &server.hub) {
+                continue;
+            }
+            return;
+        }
+
+        let end = chunks.len().min(confirmed + config.batch);
+        let sent = chunks[confirmed..end].iter().all(|chunk| {
+            let sent = server.hub.post_to(conn, chunk);
+            thread::sleep(pace);
+            sent
+        });
+
+        let check = Message::GetCfgParam {
+            param: CONFIRM_PARAM,
+        };
+        let outcome = match sent {
+            true => server
+                .hub
+                .send_to(&[sign.to_string()], &check)
+                .into_iter()
+                .find(|d| d.conn == Some(conn))
+                .map(|d| d.outcome),
+            false => None,
+        };
+
+        match outcome {
+            Some(Outcome::Acked { .. }) => {
+                retries = 0;
+                firmware.confirm(sign, end);
+            }
+            Some(Outcome::NoAck) if retries < config.max_retries => {
+                retries += 1;
+                log::warn!("{sign} didn't confirm firmware chunks {confirmed}..{end}; resending.");
+            }
+            Some(Outcome::NoAck) => {
+                log::warn!("{sign} didn't confirm firmware chunks {confirmed}..{end}; giving up.");
+                firmware.end(
+                    sign,
+                    State::Failed,
+                    Some(format!("chunks {confirmed}..{end} weren't confirmed")),
+                );
+                return;
+            }
+            _ => {
+                if !firmware.interrupt(sign, &server.hub) {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::image::Segment;
+    use super::*;
+
+    fn image(segments: &[(u16, usize)]) -> Image {
+        Image {
+            segments: segments
+                .iter()
+                .map(|(addr, len)| Segment {
+                    addr: *addr,
+                    data: (0..*len).map(|i| i as u8).collect(),
+                })
+                .collect(),
+        }
+    }
+
+    fn header(chunk: &Message) -> (u8, u16, u16) {
+        match chunk {
+            Message::FirmwareCode {
+                seq,
+                dest_addr,
+                num_bytes,
+                ..
+            } => (*seq, *dest_addr, *num_bytes),
+            msg => panic!("not a FirmwareCode: {msg:?}"),
+        }
+    }
+
+    #[test]
+    fn seq_rolls_over_past_255() {
+        let chunks = image(&[(0, 300)]).chunks(1);
+        assert_eq!(chunks.len(), 300);
+        let seqs: Vec<_> = chunks.iter().map(|c| header(c).0).collect();
+        assert_eq!(&seqs[254..258], &[254, 255, 0, 1]);
+        assert_eq!(seqs[299], 43);
+    }
+
+    #[test]
+    fn dest_addr_steps_through_each_segment() {
+        let chunks = image(&[(0x1000, 10), (0x8000, 4)]).chunks(4);
+        let headers: Vec<_> = chunks.iter().map(header).collect();
+        assert_eq!(
+            headers,
+            [
+                (0, 0x1000, 4),
+                (1, 0x1004, 4),
+                (2, 0x1008, 2),
+                (3, 0x8000, 4)
+            ]
+        );
+    }
+
+    #[test]
+    fn resumes_from_confirmed_chunk() {
+        let firmware = Firmware::new(&FirmwareConfig {
+            chunk_bytes: 4,
+            ..FirmwareConfig::default()
+        });
+        let (progress, _) = firmware.begin("sign", &image(&[(0, 40)])).unwrap();
+        assert_eq!(progress.chunks, 10);
+        firmware.confirm("sign", 6);
+
+        // With no connection, the update waits for the sign to come back.
+        assert!(!firmware.interrupt("sign", &Hub::default()));
+        assert_eq!(firmware.confirmed("sign"), None);
+        assert!(matches!(
+            firmware.begin("sign", &image(&[(0, 4)])),
+            Err(FirmwareError::Busy(_))
+        ));
+
+        let chunks = firmware.claim("sign").unwrap();
+        assert_eq!(chunks.len(), 10);
+        assert_eq!(firmware.confirmed("sign"), Some(6));
+        assert_eq!(firmware.claim("sign").map(|c| c.len()), None);
+    }
+
+    #[test]
+    fn reboot_times_out() {
+        let firmware = Firmware::new(&FirmwareConfig::default());
+        firmware.begin("sign", &image(&[(0, 4)])).unwrap();
+        firmware.end("sign", State::Rebooting, None);
+        assert_eq!(firmware.progress("sign").unwrap().state, State::Rebooting);
+
+        firmware.xs().get_mut("sign").unwrap().deadline = Some(Utc::now());
+        let progress = firmware.progress("sign").unwrap();
+        assert_eq!(progress.state, State::Failed);
+        assert!(progress.error.is_some());
+    }
+}
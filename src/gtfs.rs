@@ -0,0 +1,353 @@
+//! Stops, routes and trips read from a GTFS static feed, for making signs' stop configuration and
+//! for naming what realtime feeds refer to by id.
+//!
+//! Only `stops.txt`, `routes.txt`, `trips.txt` and `stop_times.txt` are read. A route is named by
+//! its short name, or else its long name, or else its id. A station is served by every route that
+//! serves any of its platforms. A stop served by more routes than fit in its route tag gets as
+//! many as fit, with a warning.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{Read, Seek};
+use std::path::Path;
+
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+use zip::ZipArchive;
+
+use crate::registry::SignId;
+use crate::stop_map::StopMap;
+use crate::stops::Stop;
+
+#[derive(Error, Debug)]
+pub enum GtfsError {
+    #[error("Failed i/o: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid feed archive: {0}")]
+    Zip(#[from] zip::result::ZipError),
+    #[error("Invalid {0}: {1}")]
+    Csv(&'static str, csv::Error),
+    #[error("No stop {0} in the feed")]
+    UnknownStop(String),
+    #[error("Invalid stop configuration: {0}")]
+    Invalid(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct FeedStop {
+    pub name: String,
+    /// The station the stop is part of.
+    pub parent: Option<String>,
+    /// Names of the routes serving the stop.
+    pub routes: BTreeSet<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Trip {
+    /// The name of the trip's route.
+    pub route: String,
+    pub headsign: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Feed {
+    pub stops: BTreeMap<String, FeedStop>,
+    /// Route names, by route id.
+    pub routes: HashMap<String, String>,
+    /// Trips, by trip id.
+    pub trips: HashMap<String, Trip>,
+}
+
+#[derive(Deserialize)]
+struct StopRow {
+    stop_id: String,
+    stop_name: Option<String>,
+    parent_station: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RouteRow {
+    route_id: String,
+    route_short_name: Option<String>,
+    route_long_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TripRow {
+    trip_id: String,
+    route_id: String,
+    trip_headsign: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct StopTimeRow {
+    trip_id: String,
+    stop_id: String,
+}
+
+impl Feed {
+    /// Read a feed from a GTFS zip.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, GtfsError> {
+        Feed::read(File::open(path)?)
+    }
+
+    /// Read a feed from a GTFS zip that's already open.
+    pub fn read(zip: impl Read + Seek) -> Result<Self, GtfsError> {
+        let mut zip = ZipArchive::new(zip)?;
+
+        let routes: HashMap<String, String> = rows(&mut zip, "routes.txt")?
+            .into_iter()
+            .map(|r: RouteRow| {
+                let name = r
+                    .route_short_name
+                    .or(r.route_long_name)
+                    .unwrap_or_else(|| r.route_id.clone());
+                (r.route_id, name)
+            })
+            .collect();
+        let trips: HashMap<String, Trip> = rows(&mut zip, "trips.txt")?
+            .into_iter()
+            .filter_map(|t: TripRow| {
+                let trip = Trip {
+                    route: routes.get(&t.route_id)?.clone(),
+                    headsign: t.trip_headsign.unwrap_or_default(),
+                };
+                Some((t.trip_id, trip))
+            })
+            .collect();
+
+        // This is by far the biggest file, so it's gone through a row at a time.
+        let mut served: HashMap<String, BTreeSet<String>> = HashMap::new();
+        for row in csv::Reader::from_reader(entry(&mut zip, "stop_times.txt")?).deserialize() {
+            let row: StopTimeRow = row.map_err(|e| GtfsError::Csv("stop_times.txt", e))?;
+            if let Some(trip) = trips.get(&row.trip_id) {
+                served
+                    .entry(row.stop_id)
+                    .or_default()
+                    .insert(trip.route.clone());
+            }
+        }
+
+        let stop_rows: Vec<StopRow> = rows(&mut zip, "stops.txt")?;
+        let mut stops: BTreeMap<String, FeedStop> = stop_rows
+            .iter()
+            .map(|s| {
+                let stop = FeedStop {
+                    name: s.stop_name.clone().unwrap_or_default(),
+                    parent: s.parent_station.clone(),
+                    routes: served.remove(&s.stop_id).unwrap_or_default(),
+                };
+                (s.stop_id.clone(), stop)
+            })
+            .collect();
+
+        for row in &stop_rows {
+            let Some(parent) = &row.parent_station else {
+                continue;
+            };
+            let routes = stops[&row.stop_id].routes.clone();
+            if let Some(parent) = stops.get_mut(parent) {
+                parent.routes.extend(routes);
+            }
+        }
+
+        Ok(Feed {
+            stops,
+            routes,
+            trips,
+        })
+    }
+
+    /// Each sign's stop configuration, made from the feed stops mapped to it.
+    pub fn stop_configs(&self, map: &StopMap) -> Result<BTreeMap<SignId, Vec<Stop>>, GtfsError> {
+        let mut signs: BTreeMap<SignId, Vec<Stop>> = BTreeMap::new();
+
+        for mapped in &map.stops {
+            let Some(feed_stop) = self.stops.get(&mapped.feed_stop) else {
+                return Err(GtfsError::UnknownStop(mapped.feed_stop.clone()));
+            };
+
+            let stop = Stop {
+                stop_id: mapped.stop_id,
+                title: mapped
+                    .title
+                    .clone()
+                    .unwrap_or_else(|| feed_stop.name.clone()),
+                phoneme: String::new(),
+                route_tag: route_tag(&feed_stop.routes).unwrap_or_else(|tag| {
+                    log::warn!(
+                        "{}: stop {} has too many routes to list; leaving some off.",
+                        mapped.sign,
+                        mapped.stop_id
+                    );
+                    tag
+                }),
+                snd_md5: String::new(),
+                snd_url: String::new(),
+                zero_countdown_msg: String::new(),
+                sound: None,
+            };
+            stop.check()
+                .map_err(|e| GtfsError::Invalid(format!("{}: {e}", mapped.sign)))?;
+
+            signs.entry(mapped.sign.clone()).or_default().push(stop);
+        }
+
+        for stops in signs.values_mut() {
+            stops.sort_by_key(|s| s.stop_id);
+        }
+        Ok(signs)
+    }
+}
+
+/// Route names joined with commas, or as many as fit in a `StopCfg` if they don't all.
+fn route_tag(routes: &BTreeSet<String>) -> Result<String, String> {
+    let mut tag = String::new();
+    for route in routes {
+        let sep = if tag.is_empty() { "" } else { "," };
+        if tag.len() + sep.len() + route.len() > u8::MAX.into() {
+            return Err(tag);
+        }
+        tag += sep;
+        tag += route;
+    }
+    Ok(tag)
+}
+
+fn entry<'a, R: Read + Seek>(
+    zip: &'a mut ZipArchive<R>,
+    name: &'static str,
+) -> Result<impl Read + 'a, GtfsError> {
+    Ok(zip.by_name(name)?)
+}
+
+fn rows<T: DeserializeOwned, R: Read + Seek>(
+    zip: &mut ZipArchive<R>,
+    name: &'static str,
+) -> Result<Vec<T>, GtfsError> {
+    csv::Reader::from_reader(entry(zip, name)?)
+        .deserialize()
+        .collect::<Result<_, _>>()
+        .map_err(|e| GtfsError::Csv(name, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use zip::ZipWriter;
+    use zip::write::SimpleFileOptions;
+
+    use super::*;
+    use crate::stop_map::MappedStop;
+
+    /// A feed with the given `routes.txt` rows, one trip on each route calling at every stop of a
+    /// two-platform station.
+    fn feed(routes: &[&str]) -> Feed {
+        let mut files = vec![
+            (
+                "stops.txt",
+                "stop_id,stop_name,parent_station\n\
+                 ST,Union Station,\n\
+                 ST1,Union Station Platform 1,ST\n\
+                 ST2,Union Station Platform 2,ST\n"
+                    .to_string(),
+            ),
+            (
+                "routes.txt",
+                format!(
+                    "route_id,route_short_name,route_long_name\n{}\n",
+                    routes.join("\n")
+                ),
+            ),
+        ];
+        let ids: Vec<_> = routes
+            .iter()
+            .map(|r| r.split(',').next().unwrap())
+            .collect();
+        let trips: String = ids
+            .iter()
+            .map(|id| format!("T{id},{id},To {id}\n"))
+            .collect();
+        files.push((
+            "trips.txt",
+            format!("trip_id,route_id,trip_headsign\n{trips}"),
+        ));
+        let platform = |i: usize| if i.is_multiple_of(2) { "ST1" } else { "ST2" };
+        let stop_times: String = ids
+            .iter()
+            .enumerate()
+            .map(|(i, id)| format!("T{id},{}\n", platform(i)))
+            .collect();
+        files.push(("stop_times.txt", format!("trip_id,stop_id\n{stop_times}")));
+
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, text) in files {
+            zip.start_file(name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(text.as_bytes()).unwrap();
+        }
+        Feed::read(zip.finish().unwrap()).unwrap()
+    }
+
+    fn map(feed_stop: &str, title: Option<&str>) -> StopMap {
+        StopMap {
+            stops: vec![MappedStop {
+                feed_stop: feed_stop.to_string(),
+                sign: "union-1".to_string(),
+                stop_id: 3,
+                title: title.map(str::to_string),
+            }],
+        }
+    }
+
+    #[test]
+    fn names_routes() {
+        let feed = feed(&["1,N,Judah", "2,,Owl", "3,,"]);
+        assert_eq!(feed.routes["1"], "N");
+        assert_eq!(feed.routes["2"], "Owl");
+        assert_eq!(feed.routes["3"], "3");
+        assert_eq!(feed.trips["T2"].headsign, "To 2");
+    }
+
+    #[test]
+    fn stations_get_their_platforms_routes() {
+        let feed = feed(&["1,N,", "2,J,", "3,L,"]);
+        let routes = |stop: &str| feed.stops[stop].routes.iter().cloned().collect::<Vec<_>>();
+        assert_eq!(routes("ST1"), ["L", "N"]);
+        assert_eq!(routes("ST2"), ["J"]);
+        assert_eq!(routes("ST"), ["J", "L", "N"]);
+    }
+
+    #[test]
+    fn stop_configs() {
+        let feed = feed(&["1,N,", "2,J,"]);
+        let signs = feed.stop_configs(&map("ST", None)).unwrap();
+        let stop = &signs["union-1"][0];
+        assert_eq!(stop.stop_id, 3);
+        assert_eq!(stop.title, "Union Station");
+        assert_eq!(stop.route_tag, "J,N");
+
+        let signs = feed.stop_configs(&map("ST2", Some("Platform 2"))).unwrap();
+        assert_eq!(signs["union-1"][0].title, "Platform 2");
+
+        assert!(matches!(
+            feed.stop_configs(&map("nowhere", None)),
+            Err(GtfsError::UnknownStop(_))
+        ));
+    }
+
+    #[test]
+    fn too_many_routes_are_cut_short() {
+        let routes: Vec<_> = (0..100).map(|i| format!("{i},R{i:02},")).collect();
+        let routes: Vec<_> = routes.iter().map(String::as_str).collect();
+        let feed = feed(&routes);
+
+        let signs = feed.stop_configs(&map("ST", None)).unwrap();
+        let tag = &signs["union-1"][0].route_tag;
+        assert!(tag.len() <= u8::MAX.into());
+        // Whole routes only.
+        assert!(tag.split(',').all(|r| r.len() == 3));
+        assert_eq!(tag.split(',').count(), 64);
+    }
+}
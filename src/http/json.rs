@@ -0,0 +1,241 @@
+//! The JSON forms of content items and their schedules.
+//!
+//! ```json
+//! {
+//!   "content_channel": 2,
+//!   "count_impressions": true,
+//!   "display_indefinitely": true,
+//!   "booking_id": 0,
+//!   "priority": 10,
+//!   "payloads": [
+//!     { "type": "Msg", "text": "Red line delayed" },
+//!     { "type": "SoundURL", "text": "http://10.0.0.1/delay.wav" },
+//!     { "type": "Bitmap", "hex": "00ff00ff" }
+//!   ]
+//! }
+//! ```
+//!
+//! Each payload is given either as `text` or as `hex` for raw bytes. Every field but `payloads`
+//! can be left out, taking the same values as plain-text content.
+//!
+//! Instead of giving its URL and checksum, an item can name a sound from the library with
+//! `"sound": "delay.wav"`, and the `SoundURL` and `SoundChecksum` payloads are filled in.
+//!
+//! A schedule is either `"indefinite"` or a list of windows:
+//!
+//! ```json
+//! [
+//!   { "start": "2026-11-02T06:00:00-08:00", "stop": "2026-11-02T09:30:00-08:00" },
+//!   { "start": "2026-11-03T06:00:00-08:00", "stop": "2026-11-03T09:30:00-08:00" }
+//! ]
+//! ```
+//!
+//! Times without an offset, such as `"2026-11-02T06:00"`, are local times in the sign's time zone
+//! (or the server's, if it has none), and schedules are shown in the same zone.
+//!
+//! Signs are told each window in whole minutes after the earliest start, so every window has to
+//! end within about 45 days of it.
+
+use chrono::{DateTime, Local, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::content::Content;
+use crate::msg::content::PayloadType;
+use crate::msg::content_schedule::{MAX_SCHEDULES, Schedule};
+use crate::tz;
+
+const INDEFINITE: &str = "indefinite";
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ContentJson {
+    #[serde(default = "default_channel")]
+    pub content_channel: u8,
+    #[serde(default)]
+    pub count_impressions: bool,
+    #[serde(default = "default_indefinitely")]
+    pub display_indefinitely: bool,
+    #[serde(default)]
+    pub booking_id: u16,
+    #[serde(default)]
+    pub priority: u16,
+    #[serde(default)]
+    pub payloads: Vec<PayloadJson>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sound: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PayloadJson {
+    #[serde(rename = "type")]
+    pub kind: PayloadType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hex: Option<String>,
+}
+
+fn default_channel() -> u8 {
+    2
+}
+
+fn default_indefinitely() -> bool {
+    true
+}
+
+impl ContentJson {
+    /// Check the item can be sent to a sign, and build it.
+    pub fn to_content(&self, content_id: u16) -> Result<Content, String> {
+        if self.payloads.is_empty() && self.sound.is_none() {
+            return Err("Content needs at least one payload or a sound.".to_string());
+        }
+
+        let payloads = self
+            .payloads
+            .iter()
+            .enumerate()
+            .map(|(i, p)| p.to_bytes().map_err(|e| format!("Payload {i}: {e}")))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let content = Content {
+            content_id,
+            content_channel: self.content_channel,
+            count_impressions: self.count_impressions,
+            display_indefinitely: self.display_indefinitely,
+            booking_id: self.booking_id,
+            priority: self.priority,
+            payloads,
+            sound: self.sound.clone(),
+        };
+        content.check()?;
+        Ok(content)
+    }
+}
+
+impl From<&Content> for ContentJson {
+    fn from(content: &Content) -> Self {
+        ContentJson {
+            content_channel: content.content_channel,
+            count_impressions: content.count_impressions,
+            display_indefinitely: content.display_indefinitely,
+            booking_id: content.booking_id,
+            priority: content.priority,
+            payloads: content
+                .payloads
+                .iter()
+                .map(|(kind, bytes)| PayloadJson::new(*kind, bytes))
+                .collect(),
+            sound: content.sound.clone(),
+        }
+    }
+}
+
+impl PayloadJson {
+    /// Bitmaps, and anything else that isn't UTF-8, are given as hex.
+    fn new(kind: PayloadType, bytes: &[u8]) -> Self {
+        match std::str::from_utf8(bytes) {
+            Ok(text) if kind != PayloadType::Bitmap => PayloadJson {
+                kind,
+                text: Some(text.to_string()),
+                hex: None,
+            },
+            _ => PayloadJson {
+                kind,
+                text: None,
+                hex: Some(hex::encode(bytes)),
+            },
+        }
+    }
+
+    fn to_bytes(&self) -> Result<(PayloadType, Vec<u8>), String> {
+        let bytes = match (&self.text, &self.hex) {
+            (Some(text), None) => text.as_bytes().to_vec(),
+            (None, Some(h)) => hex::decode(h).map_err(|e| format!("invalid hex: {e}"))?,
+            _ => return Err("give exactly one of text and hex.".to_string()),
+        };
+        if bytes.len() > u16::MAX.into() {
+            return Err(format!("at most {} bytes allowed.", u16::MAX));
+        }
+
+        Ok((self.kind, bytes))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WindowJson {
+    pub start: String,
+    pub stop: String,
+}
+
+/// Check a schedule can be sent to a sign in `zone`, and build it. `None` is indefinite.
+pub fn to_schedule(json: Value, zone: Option<Tz>) -> Result<Option<Vec<Schedule>>, String> {
+    if json.as_str() == Some(INDEFINITE) {
+        return Ok(None);
+    }
+    let windows: Vec<WindowJson> = serde_json::from_value(json)
+        .map_err(|e| format!("expected \"{INDEFINITE}\" or a list of windows: {e}"))?;
+
+    if windows.len() > MAX_SCHEDULES.into() {
+        return Err(format!("At most {MAX_SCHEDULES} windows allowed."));
+    }
+
+    let mut schedule = Vec::with_capacity(windows.len());
+    for (i, WindowJson { start, stop }) in windows.iter().enumerate() {
+        let (start, stop) = match (tz::instant(start, zone), tz::instant(stop, zone)) {
+            (Ok(start), Ok(stop)) => (start, stop),
+            (Err(e), _) | (_, Err(e)) => return Err(format!("Window {i}: {e}")),
+        };
+        if start >= stop {
+            return Err(format!("Window {i} doesn't stop after it starts."));
+        }
+        let (Ok(start), Ok(stop)) = (
+            u64::try_from(start.timestamp_millis()),
+            u64::try_from(stop.timestamp_millis()),
+        ) else {
+            return Err(format!("Window {i} is before 1970."));
+        };
+        schedule.push(Schedule { start, stop });
+    }
+
+    if let Some(min_time) = schedule.iter().map(|s| s.start).min() {
+        if min_time / 1_000 > u32::MAX.into() {
+            return Err("Windows must start before 2106.".to_string());
+        }
+        let max_span = u64::from(u16::MAX) * 60_000;
+        if let Some(i) = schedule.iter().position(|s| s.stop - min_time > max_span) {
+            return Err(format!(
+                "Window {i} ends more than {} minutes after the earliest start.",
+                u16::MAX
+            ));
+        }
+    }
+
+    Ok(Some(schedule))
+}
+
+/// A schedule, with times in `zone`, or the server's time zone if there's none.
+pub fn from_schedule(schedule: &Option<Vec<Schedule>>, zone: Option<Tz>) -> Value {
+    let Some(schedule) = schedule else {
+        return Value::from(INDEFINITE);
+    };
+
+    let at = |ms: u64| {
+        let at = DateTime::<Utc>::from_timestamp_millis(ms as i64).unwrap_or_default();
+        match zone {
+            Some(zone) => at.with_timezone(&zone).to_rfc3339(),
+            None => at.with_timezone(&Local).to_rfc3339(),
+        }
+    };
+    let windows: Vec<_> = schedule
+        .iter()
+        .map(|s| WindowJson {
+            start: at(s.start),
+            stop: at(s.stop),
+        })
+        .collect();
+    serde_json::to_value(windows).unwrap_or_default()
+}
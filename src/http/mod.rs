@@ -0,0 +1,636 @@
+//! The HTTP API.
+//!
+//! Content can be sent as plain text, which becomes a single `Msg` payload, or as JSON with
+//! every field of the item; see [`json`] for the JSON form.
+//!
+//! Sounds in the library (see [`crate::sounds`]) are served from `/sounds/<name>`, which is where
+//! signs fetch them from, and uploaded with `PUT` to the same place.
+//!
+//! Firmware (see [`crate::firmware`]) is sent to a sign by `PUT`ting the image to
+//! `/signs/<id>/firmware`. `?format=` is `hex`, `srec` or `bin` (the default); raw binary is
+//! written from the address given by `?base=`, or 0. `POST`ing the image to `/firmware/manifest`
+//! instead shows its manifest without sending it, and giving the manifest's CRC as `?crc32=` when
+//! sending it makes sure that's the image that's sent. An update's progress is at the same place
+//! it was sent to, and `DELETE` cancels it.
+
+mod json;
+
+use std::collections::BTreeSet;
+use std::io::Read;
+use std::sync::Arc;
+
+use rouille::{Request, Response, router};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use self::json::ContentJson;
+use crate::clocks::{ClockReport, Sync};
+use crate::content::{self, Content};
+use crate::firmware::image::{Format, Image};
+use crate::firmware::{self, FirmwareError};
+use crate::hub::Delivery;
+use crate::impressions::{Period, Total};
+use crate::phonemes;
+use crate::registry::{SignId, SignState};
+use crate::server::Server;
+use crate::settings;
+use crate::sounds::{self, SoundError};
+use crate::stops::{self, Stop};
+use crate::store::DesiredState;
+
+/// The name of the item `POST /write` sets on every sign.
+const WRITE_ITEM: &str = "write";
+
+/// The outcome of putting a content item on one sign.
+#[derive(Serialize)]
+struct Placed {
+    content_id: u16,
+    #[serde(flatten)]
+    delivery: Delivery,
+}
+
+#[derive(Serialize)]
+struct Clock {
+    #[serde(flatten)]
+    report: ClockReport,
+    history: Vec<Sync>,
+}
+
+#[derive(Serialize)]
+struct StopItem {
+    #[serde(flatten)]
+    stop: Stop,
+    /// Whether the sign has acknowledged this stop as it is since it connected.
+    loaded: bool,
+}
+
+#[derive(Serialize)]
+struct Item<'a> {
+    content_id: u16,
+    #[serde(flatten)]
+    content: ContentJson,
+    name: Option<&'a str>,
+    /// Whether the sign has acknowledged this item since it connected.
+    loaded: bool,
+}
+
+pub fn route(server: &Arc<Server>, request: &Request) -> Response {
+    router!(request,
+        (POST) (/write) => {
+            let text = match read_text(request) {
+                Ok(text) => text,
+                Err(resp) => return resp,
+            };
+            if let Err(e) = Content::text(0, &text).check() {
+                return invalid("text", e);
+            }
+            let signs: Vec<SignId> = server.registry.list().into_iter().map(|s| s.id).collect();
+
+            let make = |id| {
+                let mut content = Content::text(id, &text);
+                phonemes::fill_content(server, &mut content);
+                content
+            };
+            let placed = content::set_named_each(server, &signs, WRITE_ITEM, make)
+                .into_iter()
+                .flat_map(|(sign, placed)| placements(&sign, placed));
+            Response::json(&placed.collect::<Vec<_>>())
+        },
+        (GET) (/signs) => {
+            Response::json(&server.registry.list())
+        },
+        (GET) (/signs/{id: String}) => {
+            match server.registry.get(&id) {
+                Some(state) => Response::json(&state),
+                None => no_sign(&id),
+            }
+        },
+        (GET) (/signs/{id: String}/desired) => {
+            Response::json(&server.store.get(&id))
+        },
+        (GET) (/signs/{id: String}/content) => {
+            let Some(state) = server.registry.get(&id) else {
+                return no_sign(&id);
+            };
+            let desired = server.store.get(&id);
+
+            let items: Vec<_> = desired.content.values()
+                .map(|content| item(&state, &desired, content))
+                .collect();
+            Response::json(&items)
+        },
+        (GET) (/signs/{id: String}/content/{cid: u16}) => {
+            let Some(state) = server.registry.get(&id) else {
+                return no_sign(&id);
+            };
+            let desired = server.store.get(&id);
+
+            match desired.content.get(&cid) {
+                Some(content) => Response::json(&item(&state, &desired, content)),
+                None => no_item(&id, cid),
+            }
+        },
+        (POST) (/signs/{id: String}/content) => {
+            if server.registry.get(&id).is_none() {
+                return no_sign(&id);
+            }
+            let content = match read_content(server, request) {
+                Ok(content) => content,
+                Err(resp) => return resp,
+            };
+
+            let placed = content::create(server, &id, |content_id| Content { content_id, ..content });
+            Response::json(&placements(&id, placed))
+        },
+        (PUT) (/signs/{id: String}/content/{cid: u16}) => {
+            if server.registry.get(&id).is_none() {
+                return no_sign(&id);
+            }
+            let content = match read_content(server, request) {
+                Ok(content) => content,
+                Err(resp) => return resp,
+            };
+            match content::replace(server, &id, Content { content_id: cid, ..content }) {
+                Some(deliveries) => Response::json(&deliveries),
+                None => no_item(&id, cid),
+            }
+        },
+        (DELETE) (/signs/{id: String}/content/{cid: u16}) => {
+            if server.registry.get(&id).is_none() {
+                return no_sign(&id);
+            }
+            match content::withdraw(server, &id, cid) {
+                Some(deliveries) => Response::json(&deliveries),
+                None => no_item(&id, cid),
+            }
+        },
+        (GET) (/signs/{id: String}/content/{cid: u16}/schedule) => {
+            match server.store.get(&id).schedules.get(&cid) {
+                Some(schedule) => {
+                    Response::json(&json::from_schedule(schedule, server.config.time_zone(&id)))
+                }
+                None => no_schedule(&id, cid),
+            }
+        },
+        (PUT) (/signs/{id: String}/content/{cid: u16}/schedule) => {
+            let schedule = read_json(request, "schedule")
+                .and_then(|json| {
+                    json::to_schedule(json, server.config.time_zone(&id))
+                        .map_err(|e| invalid("schedule", e))
+                });
+            let schedule = match schedule {
+                Ok(schedule) => schedule,
+                Err(resp) => return resp,
+            };
+
+            match content::schedule(server, &id, cid, schedule) {
+                Some(deliveries) => Response::json(&deliveries),
+                None => no_item(&id, cid),
+            }
+        },
+        (DELETE) (/signs/{id: String}/content/{cid: u16}/schedule) => {
+            match content::unschedule(server, &id, cid) {
+                Some(deliveries) => Response::json(&deliveries),
+                None => no_schedule(&id, cid),
+            }
+        },
+        (GET) (/signs/{id: String}/cfg/{param: String}) => {
+            if server.registry.get(&id).is_none() {
+                return no_sign(&id);
+            }
+            let Some(param) = server.config.param(&param) else {
+                return no_param(&param);
+            };
+            Response::json(&settings::read(server, &id, param))
+        },
+        (PUT) (/signs/{id: String}/cfg/{param: String}) => {
+            if server.registry.get(&id).is_none() {
+                return no_sign(&id);
+            }
+            let Some(param) = server.config.param(&param) else {
+                return no_param(&param);
+            };
+            let value = match read_text(request) {
+                Ok(text) => text,
+                Err(resp) => return resp,
+            };
+            let Ok(value) = value.trim().parse() else {
+                return Response::text("Value must be a number from 0 to 255.").with_status_code(400);
+            };
+            Response::json(&settings::set(server, &id, param, value))
+        },
+        (DELETE) (/signs/{id: String}/cfg) => {
+            if server.registry.get(&id).is_none() {
+                return no_sign(&id);
+            }
+            Response::json(&settings::reset(server, &id))
+        },
+        (PUT) (/signs/{id: String}/profile) => {
+            if server.registry.get(&id).is_none() {
+                return no_sign(&id);
+            }
+            let profile = match read_text(request) {
+                Ok(text) => text,
+                Err(resp) => return resp,
+            };
+            match settings::apply_profile(server, &id, profile.trim()) {
+                Some(deliveries) => Response::json(&deliveries),
+                None => no_profile(profile.trim()),
+            }
+        },
+        (PUT) (/groups/{name: String}/profile) => {
+            let Some(signs) = server.config.group_members(&name) else {
+                return no_group(&name);
+            };
+            let profile = match read_text(request) {
+                Ok(text) => text,
+                Err(resp) => return resp,
+            };
+            let profile = profile.trim();
+            if !server.config.profiles.contains_key(profile) {
+                return no_profile(profile);
+            }
+
+            let deliveries = signs.iter()
+                .flat_map(|sign| settings::apply_profile(server, sign, profile).unwrap_or_default());
+            Response::json(&deliveries.collect::<Vec<_>>())
+        },
+        (GET) (/signs/{id: String}/stops) => {
+            let Some(state) = server.registry.get(&id) else {
+                return no_sign(&id);
+            };
+
+            let stops: Vec<_> = server.store.get(&id).stops.unwrap_or_default().into_iter()
+                .map(|stop| StopItem {
+                    loaded: state.stops.get(&stop.stop_id).map(Stop::to_message)
+                        == Some(stops::message(server, &stop)),
+                    stop,
+                })
+                .collect();
+            Response::json(&stops)
+        },
+        (PUT) (/signs/{id: String}/stops) => {
+            if server.registry.get(&id).is_none() {
+                return no_sign(&id);
+            }
+            let mut stops: Vec<Stop> = match read_json(request, "stops") {
+                Ok(stops) => stops,
+                Err(resp) => return resp,
+            };
+            if let Err(e) = stops::check_ids(&stops) {
+                return invalid("stops", e);
+            }
+            if let Err(e) = stops.iter_mut().try_for_each(|s| stops::fill(server, s)) {
+                return invalid("stops", e);
+            }
+            Response::json(&stops::set_all(server, &id, stops))
+        },
+        (PUT) (/signs/{id: String}/stops/{stop_id: u8}) => {
+            if server.registry.get(&id).is_none() {
+                return no_sign(&id);
+            }
+            let mut stop: Stop = match read_json(request, "stop") {
+                Ok(stop) => stop,
+                Err(resp) => return resp,
+            };
+            if stop.stop_id != stop_id {
+                return invalid("stop", format!("stop_id {} doesn't match {stop_id}", stop.stop_id));
+            }
+            if let Err(e) = stops::fill(server, &mut stop) {
+                return invalid("stop", e);
+            }
+            Response::json(&stops::set(server, &id, stop))
+        },
+        (DELETE) (/signs/{id: String}/stops/{stop_id: u8}) => {
+            match stops::remove(server, &id, stop_id) {
+                Some(deliveries) => Response::json(&deliveries),
+                None => Response::text(format!("No stop {stop_id} on sign {id}.")).with_status_code(404),
+            }
+        },
+        (POST) (/signs/{id: String}/stops/push) => {
+            if server.registry.get(&id).is_none() {
+                return no_sign(&id);
+            }
+            Response::json(&stops::push(server, &id))
+        },
+        (GET) (/clocks) => {
+            let flagged = request.get_param("flagged").is_some_and(|f| f == "true");
+            let mut reports = server.clocks.reports();
+            reports.retain(|r| !flagged || r.drifting || r.failing);
+            Response::json(&reports)
+        },
+        (GET) (/signs/{id: String}/clock) => {
+            match (server.clocks.report(&id), server.clocks.history(&id)) {
+                (Some(report), Some(history)) => Response::json(&Clock { report, history }),
+                _ if server.registry.get(&id).is_some() => {
+                    Response::text(format!("Sign {id} hasn't synced its clock.")).with_status_code(404)
+                }
+                _ => no_sign(&id),
+            }
+        },
+        (GET) (/firmware) => {
+            Response::json(&server.firmware.list())
+        },
+        (POST) (/firmware/manifest) => {
+            match read_image(request) {
+                Ok(image) => Response::json(&image.manifest()),
+                Err(resp) => resp,
+            }
+        },
+        (GET) (/signs/{id: String}/firmware) => {
+            match server.firmware.progress(&id) {
+                Some(progress) => Response::json(&progress),
+                None => no_update(&id),
+            }
+        },
+        (PUT) (/signs/{id: String}/firmware) => {
+            if server.registry.get(&id).is_none() {
+                return no_sign(&id);
+            }
+            let image = match read_image(request) {
+                Ok(image) => image,
+                Err(resp) => return resp,
+            };
+            let crc32 = image.manifest().crc32;
+            if let Some(expected) = request.get_param("crc32")
+                && !expected.eq_ignore_ascii_case(&crc32)
+            {
+                return Response::text(format!("Image's CRC-32 is {crc32}, not {expected}."))
+                    .with_status_code(409);
+            }
+            match firmware::start(server, &id, &image) {
+                Ok(progress) => Response::json(&progress),
+                Err(e @ FirmwareError::Busy(_)) => Response::text(e.to_string()).with_status_code(409),
+                Err(e) => invalid("image", e),
+            }
+        },
+        (DELETE) (/signs/{id: String}/firmware) => {
+            match server.firmware.cancel(&id) {
+                Some(progress) => Response::json(&progress),
+                None => no_update(&id),
+            }
+        },
+        (GET) (/audit) => {
+            Response::json(&settings::audit(server))
+        },
+        (GET) (/impressions) => {
+            let totals = match totals(server, request) {
+                Ok(totals) => totals,
+                Err(resp) => return resp,
+            };
+            if request.get_param("format").as_deref() != Some("csv") {
+                return Response::json(&totals);
+            }
+
+            let mut out = csv::Writer::from_writer(Vec::new());
+            let csv = totals.iter()
+                .try_for_each(|total| out.serialize(total))
+                .map_err(|e| e.to_string())
+                .and_then(|_| out.into_inner().map_err(|e| e.to_string()));
+            match csv {
+                Ok(csv) => Response::from_data("text/csv", csv),
+                Err(e) => {
+                    log::error!("Failed to write impressions CSV: {e}");
+                    Response::text("Failed to write CSV.").with_status_code(500)
+                }
+            }
+        },
+        (GET) (/sounds) => {
+            Response::json(&server.sounds.list())
+        },
+        (GET) (/sounds/{name: String}) => {
+            match server.sounds.read(&name, request.get_param("md5").as_deref()) {
+                Ok(bytes) => Response::from_data(sound_type(&name), bytes),
+                Err(SoundError::Unknown(_) | SoundError::NoLibrary) => no_sound(&name),
+                Err(e @ SoundError::Changed(_)) => Response::text(e.to_string()).with_status_code(410),
+                Err(e) => {
+                    log::error!("Failed to read sound {name}: {e}");
+                    Response::text("Failed to read sound.").with_status_code(500)
+                }
+            }
+        },
+        (PUT) (/sounds/{name: String}) => {
+            let bytes = match read_bytes(request) {
+                Ok(bytes) => bytes,
+                Err(resp) => return resp,
+            };
+            match server.sounds.put(&name, &bytes) {
+                Ok(true) => Response::json(&sounds::refresh(server, &BTreeSet::from([name]))),
+                Ok(false) => Response::json(&Vec::<Delivery>::new()),
+                Err(e @ (SoundError::InvalidName(_) | SoundError::NoLibrary)) => invalid("sound", e),
+                Err(e) => {
+                    log::error!("Failed to write sound {name}: {e}");
+                    Response::text("Failed to write sound.").with_status_code(500)
+                }
+            }
+        },
+        (POST) (/groups/{name: String}/content) => {
+            let Some(signs) = server.config.group_members(&name) else {
+                return no_group(&name);
+            };
+            let content = match read_content(server, request) {
+                Ok(content) => content,
+                Err(resp) => return resp,
+            };
+
+            let make = |content_id| Content { content_id, ..content.clone() };
+            let placed = content::create_each(server, &signs, make)
+                .into_iter()
+                .flat_map(|(sign, placed)| placements(&sign, placed));
+            Response::json(&placed.collect::<Vec<_>>())
+        },
+        _ => Response::text("No such route.").with_status_code(404)
+    )
+}
+
+fn placements(sign: &str, placed: Option<content::Added>) -> Vec<Placed> {
+    match placed {
+        Some((content_id, deliveries)) => deliveries
+            .into_iter()
+            .map(|delivery| Placed {
+                content_id,
+                delivery,
+            })
+            .collect(),
+        None => {
+            log::warn!("{sign} has no content ids left.");
+            vec![]
+        }
+    }
+}
+
+fn item<'a>(state: &SignState, desired: &'a DesiredState, content: &Content) -> Item<'a> {
+    Item {
+        content_id: content.content_id,
+        content: content.into(),
+        name: desired
+            .names
+            .iter()
+            .find(|(_, cid)| **cid == content.content_id)
+            .map(|(name, _)| name.as_str()),
+        loaded: state.acked_content.contains(&content.content_id),
+    }
+}
+
+/// Read a content item from the request body, as JSON if that's its content type and plain text
+/// otherwise. Its content id is left for the caller to fill in.
+fn read_content(server: &Server, request: &Request) -> Result<Content, Response> {
+    let is_json = request
+        .header("Content-Type")
+        .is_some_and(|t| t.starts_with("application/json"));
+    let mut content = match is_json {
+        true => {
+            let json: ContentJson = read_json(request, "content")?;
+            json.to_content(0).map_err(|e| invalid("content", e))?
+        }
+        false => Content::text(0, &read_text(request)?),
+    };
+
+    server
+        .sounds
+        .fill_content(&mut content)
+        .map_err(|e| invalid("content", e))?;
+    phonemes::fill_content(server, &mut content);
+    // Filling in the sound and phonemes adds payloads, which the item might not have room for.
+    content.check().map_err(|e| invalid("content", e))?;
+    Ok(content)
+}
+
+fn read_json<T: DeserializeOwned>(request: &Request, what: &str) -> Result<T, Response> {
+    rouille::input::json_input(request).map_err(|e| invalid(what, e))
+}
+
+/// Impression totals, per `period` (`day` or `week`), optionally only for one `sign` and
+/// `content` id. They come as CSV with `format=csv`.
+fn totals(server: &Server, request: &Request) -> Result<Vec<Total>, Response> {
+    let bad = |what: &str| Response::text(format!("Invalid {what}.")).with_status_code(400);
+
+    let period = match request.get_param("period").as_deref() {
+        None | Some("day") => Period::Day,
+        Some("week") => Period::Week,
+        Some(_) => return Err(bad("period, expected day or week")),
+    };
+    let sign = request.get_param("sign");
+    let content_id: Option<u16> = match request.get_param("content") {
+        Some(id) => Some(id.parse().map_err(|_| bad("content id"))?),
+        None => None,
+    };
+
+    let mut totals = server
+        .impressions
+        .totals(period, |sign| server.config.time_zone(sign));
+    totals.retain(|t| {
+        sign.as_ref().is_none_or(|s| &t.sign == s) && content_id.is_none_or(|id| t.content_id == id)
+    });
+    Ok(totals)
+}
+
+fn read_bytes(request: &Request) -> Result<Vec<u8>, Response> {
+    let Some(mut body) = request.data() else {
+        return Err(Response::text("Request body must be sent.").with_status_code(500));
+    };
+
+    let mut bytes = Vec::new();
+    if let Err(e) = body.read_to_end(&mut bytes) {
+        log::warn!("Can't read request body: {e}");
+        return Err(Response::text("Can't read request body.").with_status_code(500));
+    };
+
+    Ok(bytes)
+}
+
+/// The content type to serve a sound as, going by its extension.
+fn sound_type(name: &str) -> &'static str {
+    match name
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_ascii_lowercase())
+        .as_deref()
+    {
+        Some("wav") => "audio/wav",
+        Some("mp3") => "audio/mpeg",
+        Some("ogg") => "audio/ogg",
+        _ => "application/octet-stream",
+    }
+}
+
+fn read_text(request: &Request) -> Result<String, Response> {
+    let Some(mut body) = request.data() else {
+        return Err(Response::text("Request body must be sent.").with_status_code(500));
+    };
+
+    let mut text = String::new();
+    if let Err(e) = body.read_to_string(&mut text) {
+        log::warn!("Can't read request body: {e}");
+        return Err(Response::text("Can't read request body.").with_status_code(500));
+    };
+
+    Ok(text)
+}
+
+/// A firmware image, in the format given by `?format=`.
+fn read_image(request: &Request) -> Result<Image, Response> {
+    let format = match request.get_param("format") {
+        None => Format::Binary,
+        Some(name) => Format::from_name(&name)
+            .ok_or_else(|| invalid("format", "expected hex, srec or bin"))?,
+    };
+    let base = match request.get_param("base") {
+        None => 0,
+        Some(_) if format != Format::Binary => {
+            return Err(invalid("base", "only raw binary is given a base address"));
+        }
+        Some(base) => {
+            address(&base).ok_or_else(|| invalid("base", "expected an address from 0 to 0xffff"))?
+        }
+    };
+    let bytes = read_bytes(request)?;
+
+    Image::parse(format, bytes, base).map_err(|e| invalid("image", e))
+}
+
+/// An address, in decimal or in hex after `0x`.
+fn address(text: &str) -> Option<u16> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+fn invalid(what: &str, e: impl std::fmt::Display) -> Response {
+    Response::text(format!("Invalid {what}: {e}")).with_status_code(400)
+}
+
+fn no_sign(id: &str) -> Response {
+    Response::text(format!("No sign {id}.")).with_status_code(404)
+}
+
+fn no_group(name: &str) -> Response {
+    Response::text(format!("No group {name}.")).with_status_code(404)
+}
+
+fn no_param(param: &str) -> Response {
+    Response::text(format!("No parameter {param}.")).with_status_code(404)
+}
+
+fn no_profile(profile: &str) -> Response {
+    Response::text(format!("No profile {profile}.")).with_status_code(404)
+}
+
+fn no_item(id: &str, content_id: u16) -> Response {
+    Response::text(format!("No content {content_id} on sign {id}.")).with_status_code(404)
+}
+
+fn no_update(id: &str) -> Response {
+    Response::text(format!("No firmware update for sign {id}.")).with_status_code(404)
+}
+
+fn no_sound(name: &str) -> Response {
+    Response::text(format!("No sound {name}.")).with_status_code(404)
+}
+
+fn no_schedule(id: &str, content_id: u16) -> Response {
+    Response::text(format!(
+        "No schedule for content {content_id} on sign {id}."
+    ))
+    .with_status_code(404)
+}
@@ -0,0 +1,237 @@
+//! Keeps track of every live sign connection, so instructions can be delivered to all of them or
+//! to particular ones.
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crossbeam::channel;
+use serde::Serialize;
+
+use crate::msg::Message;
+use crate::registry::SignId;
+
+/// How long to wait for a sign to acknowledge a message before giving up on it.
+pub const ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub enum Instruction {
+    /// Send a message to the sign. If a reply channel is given, the sign's acknowledgement of the
+    /// message is sent on it.
+    Send(Message, Option<channel::Sender<Message>>),
+    /// Mark and sync the sign's clock.
+    Sync,
+}
+
+pub type ConnId = u64;
+
+#[derive(Default)]
+pub struct Hub {
+    next_id: AtomicU64,
+    conns: Mutex<BTreeMap<ConnId, Conn>>,
+}
+
+struct Conn {
+    sign: SignId,
+    peer: SocketAddr,
+    sender: channel::Sender<Instruction>,
+}
+
+/// A connection's membership of the hub. Dropping it takes the connection out of the hub.
+pub struct Connection {
+    hub: Arc<Hub>,
+    pub id: ConnId,
+    pub instructions: channel::Receiver<Instruction>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.hub.conns().remove(&self.id);
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Delivery {
+    pub sign: SignId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub conn: Option<ConnId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer: Option<SocketAddr>,
+    #[serde(flatten)]
+    pub outcome: Outcome,
+    /// The sign's acknowledgement, for anything it carries beyond the error code.
+    #[serde(skip)]
+    pub ack: Option<Message>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Outcome {
+    /// The sign acknowledged the message with this error code; zero is success.
+    Acked { error: u8 },
+    /// The sign didn't acknowledge the message in time.
+    NoAck,
+    /// The connection went away before the message could be sent.
+    Disconnected,
+    /// The sign isn't connected.
+    Offline,
+}
+
+impl Hub {
+    pub fn connect(self: &Arc<Self>, sign: SignId, peer: SocketAddr) -> Connection {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, instructions) = channel::unbounded();
+        self.conns().insert(id, Conn { sign, peer, sender });
+
+        Connection {
+            hub: self.clone(),
+            id,
+            instructions,
+        }
+    }
+
+    /// Give every connection an instruction, without waiting for any reply.
+    pub fn broadcast(&self, instruction: impl Fn() -> Instruction) {
+        for (id, conn) in self.conns().iter() {
+            if let Err(e) = conn.sender.send(instruction()) {
+                log::warn!("Failed to instruct connection {id} ({}): {e}", conn.sign);
+            }
+        }
+    }
+
+    /// Send a message to every connected sign and wait for their acknowledgements.
+    pub fn send_all(&self, msg: &Message) -> Vec<Delivery> {
+        let targets = self
+            .conns()
+            .iter()
+            .map(|c| (Target::from(c), msg.clone()))
+            .collect();
+        deliver(targets)
+    }
+
+    /// Send a message to each of the given signs that is connected and wait for their
+    /// acknowledgements. Signs that aren't connected are reported as offline.
+    pub fn send_to(&self, signs: &[SignId], msg: &Message) -> Vec<Delivery> {
+        let msgs: Vec<_> = signs
+            .iter()
+            .map(|sign| (sign.clone(), msg.clone()))
+            .collect();
+        self.send_each(&msgs)
+    }
+
+    /// Send each sign its own message and wait for all of the acknowledgements together, so
+    /// signs that don't answer hold things up no longer than one would. Signs that aren't
+    /// connected are reported as offline.
+    pub fn send_each(&self, msgs: &[(SignId, Message)]) -> Vec<Delivery> {
+        let mut targets = Vec::new();
+        let mut offline = Vec::new();
+        {
+            let conns = self.conns();
+            for (sign, msg) in msgs {
+                let len = targets.len();
+                targets.extend(
+                    conns
+                        .iter()
+                        .filter(|(_, c)| &c.sign == sign)
+                        .map(|c| (Target::from(c), msg.clone())),
+                );
+                if targets.len() == len {
+                    offline.push(sign.clone());
+                }
+            }
+        }
+
+        let mut deliveries = deliver(targets);
+        deliveries.extend(offline.into_iter().map(|sign| Delivery {
+            sign,
+            conn: None,
+            peer: None,
+            outcome: Outcome::Offline,
+            ack: None,
+        }));
+        deliveries
+    }
+
+    /// A sign's latest connection, if it's connected.
+    pub fn connection(&self, sign: &str) -> Option<ConnId> {
+        self.conns()
+            .iter()
+            .rev()
+            .find(|(_, c)| c.sign == sign)
+            .map(|(id, _)| *id)
+    }
+
+    /// Send a message on one particular connection, without waiting for any reply. False if the
+    /// connection has gone.
+    pub fn post_to(&self, conn: ConnId, msg: &Message) -> bool {
+        self.conns()
+            .get(&conn)
+            .is_some_and(|c| c.sender.send(Instruction::Send(msg.clone(), None)).is_ok())
+    }
+
+    fn conns(&self) -> MutexGuard<'_, BTreeMap<ConnId, Conn>> {
+        self.conns.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+struct Target {
+    sign: SignId,
+    conn: ConnId,
+    peer: SocketAddr,
+    sender: channel::Sender<Instruction>,
+}
+
+impl From<(&ConnId, &Conn)> for Target {
+    fn from((id, conn): (&ConnId, &Conn)) -> Self {
+        Target {
+            sign: conn.sign.clone(),
+            conn: *id,
+            peer: conn.peer,
+            sender: conn.sender.clone(),
+        }
+    }
+}
+
+/// Send each target its message, then wait for all of the acknowledgements together.
+fn deliver(targets: Vec<(Target, Message)>) -> Vec<Delivery> {
+    let pending: Vec<_> = targets
+        .into_iter()
+        .map(|(t, msg)| {
+            let (reply, ack) = channel::bounded(1);
+            let sent = t.sender.send(Instruction::Send(msg, Some(reply)));
+            (t, sent.map(|_| ack))
+        })
+        .collect();
+
+    let deadline = Instant::now() + ACK_TIMEOUT;
+    pending
+        .into_iter()
+        .map(|(t, ack)| {
+            let (outcome, ack) = match ack {
+                Err(_) => (Outcome::Disconnected, None),
+                Ok(ack) => wait_for_ack(&ack, deadline),
+            };
+            Delivery {
+                sign: t.sign,
+                conn: Some(t.conn),
+                peer: Some(t.peer),
+                outcome,
+                ack,
+            }
+        })
+        .collect()
+}
+
+fn wait_for_ack(ack: &channel::Receiver<Message>, deadline: Instant) -> (Outcome, Option<Message>) {
+    match ack.recv_deadline(deadline) {
+        Ok(msg) => (
+            Outcome::Acked {
+                error: msg.ack_error().unwrap_or(0),
+            },
+            Some(msg),
+        ),
+        Err(channel::RecvTimeoutError::Timeout) => (Outcome::NoAck, None),
+        Err(channel::RecvTimeoutError::Disconnected) => (Outcome::Disconnected, None),
+    }
+}
@@ -0,0 +1,313 @@
+//! Impression counts for content that signs count, collected by asking each sign for them with
+//! `ContentCount` every so often.
+//!
+//! A sign keeps one counter per hour of the day for each counted item, and the counters only go
+//! up until the item is replaced or the sign restarts. A sample is those 24 counters as they
+//! stood at one moment. The impressions between two samples are however much each counter grew,
+//! and are put down to the last time its hour came round in the sign's time zone; a counter that
+//! went down was reset, so all of its new value counts. So does the first sample of each booking,
+//! as the item was replaced to give it its new booking.
+//!
+//! Samples are appended to a CSV file with a header line, one sample per line:
+//!
+//! ```text
+//! at,sign,content_id,booking_id,h00,h01,...,h23
+//! ```
+
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+use chrono::{DateTime, Datelike, Days, Local, NaiveDate, NaiveDateTime, Timelike, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::msg::Message;
+use crate::registry::SignId;
+use crate::server::Server;
+
+#[derive(Error, Debug)]
+pub enum ImpressionsError {
+    #[error("Failed i/o: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid CSV: {0}")]
+    Csv(#[from] csv::Error),
+    #[error("Malformed sample on line {0}: {1}")]
+    Malformed(u64, String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub at: DateTime<Utc>,
+    pub sign: SignId,
+    pub content_id: u16,
+    /// The booking the item was shown for when the sample was taken.
+    pub booking_id: u16,
+    /// Impressions so far in each hour of the day.
+    pub counts: [u16; 24],
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Period {
+    Day,
+    /// Weeks starting on Monday.
+    Week,
+}
+
+/// The impressions of one item on one sign over one period.
+#[derive(Debug, Serialize)]
+pub struct Total {
+    /// The first day of the period, in the sign's time zone, or the server's if it has none.
+    pub start: NaiveDate,
+    pub sign: SignId,
+    pub content_id: u16,
+    pub booking_id: u16,
+    pub impressions: u64,
+}
+
+pub struct Impressions {
+    path: Option<PathBuf>,
+    samples: Mutex<Vec<Sample>>,
+}
+
+impl Impressions {
+    /// Load the samples kept at `path`, starting with none if it doesn't exist yet. Without a
+    /// path, nothing is persisted.
+    pub fn open(path: Option<PathBuf>) -> Result<Self, ImpressionsError> {
+        let samples = match &path {
+            Some(path) if path.exists() => read(path)?,
+            _ => Vec::new(),
+        };
+
+        Ok(Impressions {
+            path,
+            samples: Mutex::new(samples),
+        })
+    }
+
+    pub fn add(&self, sample: Sample) {
+        if let Some(path) = &self.path
+            && let Err(e) = append(path, &sample)
+        {
+            log::error!("Failed to save impressions: {e}");
+        }
+        self.samples().push(sample);
+    }
+
+    /// Total impressions per period, for each item on each sign that has been sampled. Periods
+    /// are in each sign's `zone`, or the server's time zone.
+    pub fn totals(&self, period: Period, zone: impl Fn(&str) -> Option<Tz>) -> Vec<Total> {
+        let mut last: BTreeMap<(&str, u16, u16), [u16; 24]> = BTreeMap::new();
+        let mut totals: BTreeMap<(NaiveDate, &str, u16, u16), u64> = BTreeMap::new();
+
+        let samples = self.samples();
+        for sample in samples.iter() {
+            let at = match zone(&sample.sign) {
+                Some(zone) => sample.at.with_timezone(&zone).naive_local(),
+                None => sample.at.with_timezone(&Local).naive_local(),
+            };
+            let prev = last
+                .insert(
+                    (&sample.sign, sample.content_id, sample.booking_id),
+                    sample.counts,
+                )
+                .unwrap_or([0; 24]);
+
+            for (hour, (&now, &before)) in sample.counts.iter().zip(&prev).enumerate() {
+                let grew = if now >= before { now - before } else { now };
+                if grew == 0 {
+                    continue;
+                }
+
+                let start = period.start(last_day_with_hour(at, hour as u32));
+                *totals
+                    .entry((start, &sample.sign, sample.content_id, sample.booking_id))
+                    .or_default() += u64::from(grew);
+            }
+        }
+
+        totals
+            .into_iter()
+            .map(
+                |((start, sign, content_id, booking_id), impressions)| Total {
+                    start,
+                    sign: sign.to_string(),
+                    content_id,
+                    booking_id,
+                    impressions,
+                },
+            )
+            .collect()
+    }
+
+    fn samples(&self) -> MutexGuard<'_, Vec<Sample>> {
+        self.samples.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Period {
+    fn start(self, day: NaiveDate) -> NaiveDate {
+        match self {
+            Period::Day => day,
+            Period::Week => day - Days::new(day.weekday().num_days_from_monday().into()),
+        }
+    }
+}
+
+/// The day of the last time `hour` started, as of `at`.
+fn last_day_with_hour(at: NaiveDateTime, hour: u32) -> NaiveDate {
+    let today = at.date();
+    if hour <= at.hour() {
+        today
+    } else {
+        today - Days::new(1)
+    }
+}
+
+/// Ask every connected sign how often each of its counted items has been shown, and keep the
+/// answers.
+pub fn collect(server: &Server) {
+    for sign in server.registry.list().into_iter().filter(|s| s.online) {
+        let desired = server.store.get(&sign.id);
+
+        for content in desired.content.values().filter(|c| c.count_impressions) {
+            let msg = Message::ContentCount {
+                content_id: content.content_id,
+            };
+
+            for delivery in server.hub.send_to(std::slice::from_ref(&sign.id), &msg) {
+                match delivery.ack {
+                    Some(Message::AckContentCount { error: 0, data, .. }) => {
+                        server.impressions.add(Sample {
+                            at: Utc::now(),
+                            sign: sign.id.clone(),
+                            content_id: content.content_id,
+                            booking_id: content.booking_id,
+                            counts: data,
+                        })
+                    }
+                    _ => log::warn!(
+                        "Counting impressions of content {} on {}: {:?}",
+                        content.content_id,
+                        sign.id,
+                        delivery.outcome
+                    ),
+                }
+            }
+        }
+    }
+}
+
+fn append(path: &Path, sample: &Sample) -> Result<(), ImpressionsError> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let is_new = file.metadata()?.len() == 0;
+    let mut out = csv::Writer::from_writer(file);
+
+    if is_new {
+        let mut header = vec![
+            "at".to_string(),
+            "sign".to_string(),
+            "content_id".to_string(),
+            "booking_id".to_string(),
+        ];
+        header.extend((0..24).map(|h| format!("h{h:02}")));
+        out.write_record(&header)?;
+    }
+
+    let mut record = vec![
+        sample.at.to_rfc3339(),
+        sample.sign.clone(),
+        sample.content_id.to_string(),
+        sample.booking_id.to_string(),
+    ];
+    record.extend(sample.counts.iter().map(u16::to_string));
+    out.write_record(&record)?;
+    out.flush()?;
+    Ok(())
+}
+
+fn read(path: &Path) -> Result<Vec<Sample>, ImpressionsError> {
+    let mut samples = Vec::new();
+
+    for record in csv::Reader::from_path(path)?.records() {
+        let record = record?;
+        let line = record.position().map_or(0, |p| p.line());
+        let malformed =
+            |e: &dyn std::fmt::Display| ImpressionsError::Malformed(line, e.to_string());
+
+        if record.len() != 28 {
+            return Err(malformed(&format!(
+                "expected 28 fields, got {}",
+                record.len()
+            )));
+        }
+
+        let mut counts = [0; 24];
+        for (count, field) in counts.iter_mut().zip(record.iter().skip(4)) {
+            *count = field.parse().map_err(|e| malformed(&e))?;
+        }
+
+        samples.push(Sample {
+            at: record[0].parse().map_err(|e| malformed(&e))?,
+            sign: record[1].to_string(),
+            content_id: record[2].parse().map_err(|e| malformed(&e))?,
+            booking_id: record[3].parse().map_err(|e| malformed(&e))?,
+            counts,
+        });
+    }
+
+    Ok(samples)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(at: &str, booking_id: u16, counts: &[(usize, u16)]) -> Sample {
+        let mut sample = Sample {
+            at: at.parse().unwrap(),
+            sign: "platform-1".to_string(),
+            content_id: 4,
+            booking_id,
+            counts: [0; 24],
+        };
+        for &(hour, count) in counts {
+            sample.counts[hour] = count;
+        }
+        sample
+    }
+
+    #[test]
+    fn totals_bookings_from_their_own_first_sample() {
+        let impressions = Impressions::open(None).unwrap();
+        impressions.add(sample("2024-03-04T10:30:00Z", 1, &[(10, 5)]));
+        impressions.add(sample("2024-03-04T11:10:00Z", 1, &[(10, 8), (11, 2)]));
+        // The item was replaced for the new booking, so its counters started again; all of this
+        // sample's 3 are the new booking's, not 1 more than the old booking's 2.
+        impressions.add(sample("2024-03-04T11:30:00Z", 2, &[(11, 3)]));
+        impressions.add(sample("2024-03-04T11:50:00Z", 2, &[(11, 4)]));
+
+        let totals = impressions.totals(Period::Day, |_| Some(Tz::UTC));
+        let totals: Vec<_> = totals
+            .iter()
+            .map(|t| {
+                (
+                    t.start.to_string(),
+                    t.content_id,
+                    t.booking_id,
+                    t.impressions,
+                )
+            })
+            .collect();
+        assert_eq!(
+            totals,
+            [
+                ("2024-03-04".to_string(), 4, 1, 10),
+                ("2024-03-04".to_string(), 4, 2, 4),
+            ]
+        );
+    }
+}
@@ -2,21 +2,44 @@ use std::io::Write;
 use std::net::TcpStream;
 
 use crate::msg::Message;
+use crate::record::{Direction, Recorder};
 use crossbeam::channel;
-use log;
 
+pub mod clocks;
+pub mod config;
+pub mod content;
+pub mod firmware;
+pub mod gtfs;
+pub mod http;
+pub mod hub;
+pub mod impressions;
 pub mod msg;
+pub mod phonemes;
+pub mod predictions;
+pub mod proxy;
+pub mod record;
+pub mod registry;
+pub mod replay;
+pub mod server;
+pub mod settings;
+pub mod sounds;
+pub mod stop_map;
+pub mod stops;
+pub mod store;
+pub mod tz;
 
 /// Wrap a sign to provide channels for messages. Anything sent will be written, and anything
-/// received will be sent.
+/// received will be sent. If a recorder is given, every frame in either direction is recorded.
 pub fn run(
     stream: TcpStream,
+    recorder: Option<Recorder>,
 ) -> (
     channel::Sender<msg::Message>,
     channel::Receiver<msg::Message>,
 ) {
     let mut reader_stream = stream.try_clone().unwrap();
     let mut writer_stream = stream.try_clone().unwrap();
+    let writer_recorder = recorder.clone();
 
     let (send_parsed_from_tcp, recv_parsed_from_tcp) = channel::unbounded();
     let (send_to_tcp, recv_to_tcp) = channel::unbounded::<Message>();
@@ -24,7 +47,13 @@ pub fn run(
     // Reader thread from TCP.
     std::thread::spawn(move || {
         loop {
-            let msg = match Message::decode(&mut reader_stream) {
+            let msg = match msg::read_frame(&mut reader_stream).and_then(|frame| {
+                let msg = Message::decode(&frame[..])?;
+                if let Some(recorder) = &recorder {
+                    recorder.record(Direction::In, &frame, &msg);
+                }
+                Ok(msg)
+            }) {
                 Ok(m) => m,
                 Err(e) => {
                     log::error!("Failed reading from TCP stream: {e}");
@@ -43,8 +72,15 @@ pub fn run(
         for msg in recv_to_tcp.into_iter() {
             log::info!("Sending: {msg:?}");
 
-            let msg = msg.encode();
-            if let Err(e) = (&mut writer_stream).write_all(&msg) {
+            let frame = match &writer_recorder {
+                Some(recorder) => {
+                    let frame = msg.clone().encode();
+                    recorder.record(Direction::Out, &frame, &msg);
+                    frame
+                }
+                None => msg.encode(),
+            };
+            if let Err(e) = writer_stream.write_all(&frame) {
                 log::error!("Failed to send msg over TCP: {e}");
             };
         }
@@ -1,17 +1,24 @@
-use std::io::Read;
 use std::net::{TcpListener, TcpStream};
 use std::sync::Arc;
 use std::thread;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 
-use anyhow::{Result, bail};
-use chrono::{FixedOffset, Offset};
+use anyhow::{Context, Result, bail};
+use chrono::{Local, Offset, Utc};
 use crossbeam::channel::{self, select};
-use env_logger;
-use log;
-use nextbus_sign_server::msg::{Message, content::PayloadType};
+use nextbus_sign_server::config::Config;
+use nextbus_sign_server::firmware;
+use nextbus_sign_server::http;
+use nextbus_sign_server::hub::{ACK_TIMEOUT, Instruction};
+use nextbus_sign_server::impressions;
+use nextbus_sign_server::msg::Message;
+use nextbus_sign_server::predictions;
+use nextbus_sign_server::record::Recorder;
+use nextbus_sign_server::registry::SignId;
+use nextbus_sign_server::server::{ClockMark, Server, respond_to};
+use nextbus_sign_server::sounds;
+use nextbus_sign_server::tz;
 use rand::{Rng, rng};
-use rouille::Response;
 
 fn main() {
     env_logger::init();
@@ -21,10 +28,14 @@ fn main() {
     }
 
     fn inner() -> Result<()> {
-        let (s, r) = channel::unbounded();
-        let r = Arc::new(r);
-        let s = Arc::new(s);
-
+        let config = match std::env::args_os().nth(1) {
+            Some(path) => Config::load(&path)
+                .with_context(|| format!("Couldn't load config from {}", path.display()))?,
+            None => Config::default(),
+        };
+        let server = Arc::new(Server::new(config).context("Couldn't start server")?);
+
+        let sv = server.clone();
         thread::spawn(move || {
             let listener = TcpListener::bind("0.0.0.0:4502").unwrap();
 
@@ -32,9 +43,9 @@ fn main() {
                 match stream {
                     Err(e) => log::warn!("Can't get stream: {e}"),
                     Ok(c) => {
-                        let r = r.clone();
+                        let sv = sv.clone();
                         thread::spawn(move || {
-                            if let Err(e) = handle(c, r) {
+                            if let Err(e) = handle(c, sv) {
                                 log::warn!("Couldn't handle connection: {e}");
                             }
                         });
@@ -43,115 +54,119 @@ fn main() {
             }
         });
 
-        let sp = s.clone();
+        let sv = server.clone();
         thread::spawn(move || {
+            let interval = Duration::from_secs(sv.config.clock.sync_secs);
             loop {
-                if let Err(e) = sp.send(Instruction::Sync) {
-                    log::error!("Failed to instruct sync: {e}");
-                }
-                thread::sleep(Duration::from_mins(1));
+                thread::sleep(interval);
+                sv.hub.broadcast(|| Instruction::Sync);
             }
         });
 
-        rouille::start_server("0.0.0.0:8080", move |request| {
-            if request.method() == "POST" && request.url() == "/write" {
-                let Some(mut body) = request.data() else {
-                    return Response::text("Request body must be sent.").with_status_code(500);
-                };
-
-                let mut text = String::new();
-                if let Err(e) = body.read_to_string(&mut text) {
-                    log::warn!("Can't read request body: {e}");
-                    return Response::text("Can't read request body.").with_status_code(500);
-                };
-
-                if let Err(e) = s.send(Instruction::SetText(text)) {
-                    log::error!("Channel sending error: {e}");
-                    return Response::text("Can't send over channel.").with_status_code(500);
-                };
-
-                Response::empty_404().with_status_code(200) // no empty_200() response lol
-            } else {
-                Response::text("Only route is POST /write.").with_status_code(404)
+        predictions::start(&server).context("Couldn't start predictions")?;
+
+        let sv = server.clone();
+        thread::spawn(move || {
+            let interval = sv.config.count_interval_secs.unwrap_or(15 * 60);
+            loop {
+                thread::sleep(Duration::from_secs(interval));
+                impressions::collect(&sv);
             }
-        })
+        });
+
+        if server.config.sound_dir.is_some() {
+            let sv = server.clone();
+            thread::spawn(move || {
+                let interval = sv.config.sound_scan_secs.unwrap_or(60);
+                loop {
+                    thread::sleep(Duration::from_secs(interval));
+                    sounds::rescan(&sv);
+                }
+            });
+        }
+
+        rouille::start_server("0.0.0.0:8080", move |request| http::route(&server, request))
     }
 }
 
-#[derive(Clone, Copy)]
-struct ClockMark {
-    epoch_sec: u32, // 2038 will never happen.
-    seq_num: u8,
-    offset: FixedOffset,
-}
+fn handle(stream: TcpStream, server: Arc<Server>) -> Result<()> {
+    let addr = stream.peer_addr()?;
+    let id = server.registry.connect(addr);
+    log::info!("Handling connection from: {id} ({addr})");
 
-enum Instruction {
-    SetText(String),
-    Sync,
+    let result = serve(stream, &id, &server);
+    server.registry.disconnect(&id, addr);
+
+    result
 }
 
-fn handle(stream: TcpStream, msg_ch: Arc<channel::Receiver<Instruction>>) -> Result<()> {
+fn serve(stream: TcpStream, id: &SignId, server: &Arc<Server>) -> Result<()> {
     let addr = stream.peer_addr()?;
-    log::info!("Handling connection from: {addr}");
-
-    let (s, r) = nextbus_sign_server::run(stream);
 
-    let mut clk_mark = None;
+    let recorder = match &server.config.record_dir {
+        Some(dir) => {
+            let path = dir.join(format!("{id}.rec"));
+            Some(
+                Recorder::create(&path, addr)
+                    .with_context(|| format!("Couldn't open recording at {}", path.display()))?,
+            )
+        }
+        None => None,
+    };
+
+    let (s, r) = nextbus_sign_server::run(stream, recorder);
+    let conn = server.hub.connect(id.clone(), addr);
+
+    // Whatever the sign was showing may be gone, so set it up again, and carry on with any firmware
+    // update it was in the middle of. These wait on acks that this thread processes, so they have
+    // to happen elsewhere.
+    let (sv, sign) = (server.clone(), id.clone());
+    thread::spawn(move || {
+        sv.restore(&sign);
+        firmware::resume(&sv, &sign);
+    });
+
+    // Signs keep time badly, so sync the clock straight away rather than at the next interval.
+    let mut clk_mark = mark_clock(&s, id, server);
+    // Messages we've sent, for as long as we'd accept an ack for them.
+    let mut pending_acks: Vec<(Message, Option<channel::Sender<Message>>, Instant)> = Vec::new();
 
     loop {
         select!(
-            recv(msg_ch) -> msg => {
+            recv(conn.instructions) -> msg => {
                 match msg {
-                    Ok(Instruction::SetText(msg)) => {
-                        if let Err(e) = s.send(Message::ContentMsg {
-                            content_id: 0x11,
-                            content_channel: 2,
-                            count_impressions: false,
-                            display_indefinitely: true,
-                            booking_id: 0,
-                            priority: 0,
-                            payloads: vec![(
-                                PayloadType::Msg,
-                                msg.as_bytes().to_vec(),
-                            )],
-                        }) {
-                            log::error!("Failed setting message: {msg}: {e}");
+                    Ok(Instruction::Send(msg, reply)) => {
+                        pending_acks.retain(|(_, _, at)| at.elapsed() < ACK_TIMEOUT);
+                        pending_acks.push((msg.clone(), reply, Instant::now()));
+                        if let Err(e) = s.send(msg) {
+                            log::error!("Failed to send message to sign: {e}");
                         }
                     },
                     Ok(Instruction::Sync) => {
-                        log::info!("Requesting clock mark.");
-
-                        let time = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
-                            Err(e) => {
-                                log::warn!("Epcoh was not before current time: {e}. Skipping clock sync.");
-                                continue;
-                            },
-                            Ok(time) => time.as_secs().try_into().unwrap_or_else(|_| {
-                                log::warn!("Time overflowing 32 bit repr.");
-                                time.as_secs() as u32
-                            }),
-                        };
-
-                        clk_mark = Some(ClockMark {
-                            seq_num: rng().next_u32() as u8,
-                            epoch_sec: time,
-                            offset: chrono::Local::now().offset().fix(),
-                        });
-
-                        if let Err(e) = s.send(Message::MarkClock { sequence: clk_mark.unwrap().seq_num }) {
-                            log::error!("Failed to send MarkClock: {e}");
+                        if let Some(mark) = mark_clock(&s, id, server) {
+                            clk_mark = Some(mark);
                         }
                     },
-                    Err(e) => log::error!("Failed to receive text message from channel: {e}"),
+                    Err(e) => log::error!("Failed to receive instruction from channel: {e}"),
                 }
             },
             recv(r) -> msg => match msg {
                 Ok(msg) => {
                     log::info!("Recv'd: {msg:?}");
-                    if let Some(resp) = respond_to(msg, clk_mark) {
-                        if let Err(e) = s.send(resp) {
-                            log::error!("Failed to send sign message to channel: {e}");
-                        };
+                    server.registry.observe(id, &msg);
+                    server.clocks.observe(id, &msg);
+                    server.firmware.observe(id, &msg);
+                    if let Some(i) = pending_acks.iter().position(|(sent, _, _)| msg.is_ack_for(sent)) {
+                        let (sent, reply, _) = pending_acks.remove(i);
+                        server.registry.observe_ack(id, &sent, &msg);
+                        if let Some(reply) = reply {
+                            let _ = reply.send(msg.clone());
+                        }
+                    }
+                    if let Some(resp) = respond_to(msg, clk_mark.as_ref())
+                        && let Err(e) = s.send(resp)
+                    {
+                        log::error!("Failed to send sign message to channel: {e}");
                     }
                 },
                 Err(e) => {
@@ -163,27 +178,34 @@ fn handle(stream: TcpStream, msg_ch: Arc<channel::Receiver<Instruction>>) -> Res
     }
 }
 
-fn respond_to(msg: Message, clk_mark: Option<ClockMark>) -> Option<Message> {
-    match msg {
-        Message::Ping { seq_num } => Some(Message::Pong { seq_num }),
-        Message::AckMarkClock { seq_num } => match clk_mark {
-            None => {
-                log::warn!("Received unrequested AckMarkClock.");
-                None
-            }
-            Some(ClockMark { seq_num: x, .. }) if x != seq_num => {
-                log::warn!("Wrong MarkClock Ack'd: Saw {seq_num}, expected {x}");
-                None
-            }
-            Some(mark) => {
-                Some(Message::SyncClock {
-                    epoch_time_sec: mark.epoch_sec,
-                    seq_num,
-                    tz: format!("GMT{}", mark.offset),
-                    zone_offset: 0, // unused so far as I can tell
-                })
-            }
+/// Start a clock sync by sending `MarkClock`. The mark, to sync to once the sign acks it.
+fn mark_clock(s: &channel::Sender<Message>, id: &SignId, server: &Server) -> Option<ClockMark> {
+    log::info!("Requesting clock mark.");
+
+    let time = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        Err(e) => {
+            log::warn!("Epcoh was not before current time: {e}. Skipping clock sync.");
+            return None;
+        }
+        Ok(time) => time.as_secs().try_into().unwrap_or_else(|_| {
+            log::warn!("Time overflowing 32 bit repr.");
+            time.as_secs() as u32
+        }),
+    };
+
+    let mark = ClockMark {
+        seq_num: rng().next_u32() as u8,
+        epoch_sec: time,
+        tz: match server.config.time_zone(id) {
+            Some(zone) => server.tz_rules.posix_tz(zone, Utc::now()),
+            None => tz::posix_fixed(Local::now().offset().fix()),
         },
-        _ => None,
+    };
+
+    if let Err(e) = s.send(Message::MarkClock {
+        sequence: mark.seq_num,
+    }) {
+        log::error!("Failed to send MarkClock: {e}");
     }
+    Some(mark)
 }
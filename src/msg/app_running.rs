@@ -1,6 +1,8 @@
+use serde::Serialize;
+
 use crate::msg::Message;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
 pub enum AppRunningReason {
     Undiscernable,
     Powerup,
@@ -33,6 +35,25 @@ impl From<u8> for AppRunningReason {
     }
 }
 
+impl From<AppRunningReason> for u8 {
+    fn from(x: AppRunningReason) -> Self {
+        match x {
+            AppRunningReason::Undiscernable => 0,
+            AppRunningReason::Powerup => 1,
+            AppRunningReason::Watchdog => 2,
+            AppRunningReason::ServerOrder => 3,
+            AppRunningReason::NewFirmware => 4,
+            AppRunningReason::NoServerContact => 5,
+            AppRunningReason::Redirected => 6,
+            AppRunningReason::DroppedConnection => 7,
+            AppRunningReason::BadAuthentication => 8,
+            AppRunningReason::FatalError => 9,
+            // Anything unrecognised decodes as Unknown, so this keeps it unrecognised.
+            AppRunningReason::Unknown => u8::MAX,
+        }
+    }
+}
+
 pub fn new(payload: Vec<u8>) -> Message {
     Message::AppRunning {
         seq_num: payload[0],
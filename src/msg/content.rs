@@ -1,6 +1,8 @@
+use serde::{Deserialize, Serialize};
+
 use crate::msg::Message;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum PayloadType {
     Msg = 0,
     Phoneme = 1,
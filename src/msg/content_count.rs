@@ -9,9 +9,9 @@ pub fn new(payload: Vec<u8>) -> Message {
 pub fn new_ack(payload: Vec<u8>) -> Message {
     let mut data: [u16; 24] = [0; 24];
 
-    for i in 0..24 {
+    for (i, d) in data.iter_mut().enumerate() {
         let j = i * 2 + 3;
-        data[i] = u16::from_be_bytes([payload[j], payload[j + 1]]);
+        *d = u16::from_be_bytes([payload[j], payload[j + 1]]);
     }
 
     Message::AckContentCount {
@@ -1,3 +1,5 @@
+use serde::{Deserialize, Serialize};
+
 use crate::msg::Message;
 
 // Number of schedules ranges [0x00, 0xFF), freeing up
@@ -52,7 +54,7 @@ pub fn new_ack(payload: Vec<u8>) -> Message {
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Schedule {
     // both milliseconds, presumably
     pub start: u64,
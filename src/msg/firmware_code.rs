@@ -10,7 +10,7 @@ pub fn new(mut payload: Vec<u8>) -> Message {
     };
     assert_eq!(
         code_chunk.len(),
-        num_bytes.into(),
+        usize::from(num_bytes),
         "reported code chunk size is a lie"
     );
     Message::FirmwareCode {
@@ -1,5 +1,5 @@
 mod ack_content;
-mod app_running;
+pub mod app_running;
 mod auth_confirm;
 mod auth_request;
 mod cfg_params;
@@ -29,9 +29,47 @@ pub enum DecodeError {
     ChecksumMismatch(u16, u16),
     #[error("Unknown message of type: {0}")]
     UnknownMessage(u8),
+    /// The length, and the header bytes it was read from.
+    #[error("Frame length {0} is shorter than the frame header")]
+    BadLength(u16, [u8; 3]),
 }
 
-#[derive(Debug)]
+/// Read a single raw frame (type, length, payload and checksum) off the stream without
+/// interpreting it. The result can be handed to [`Message::decode`].
+pub fn read_frame<I: Read>(mut stream: I) -> Result<Vec<u8>, DecodeError> {
+    let mut header = [0; 3];
+    stream.read_exact(&mut header)?;
+
+    let len = u16::from_be_bytes([header[1], header[2]]);
+    if len < 5 {
+        return Err(DecodeError::BadLength(len, header));
+    }
+
+    let mut frame = header.to_vec();
+    frame.resize(len as usize, 0);
+    stream.read_exact(&mut frame[3..])?;
+
+    Ok(frame)
+}
+
+/// Frame a payload of the given message type, without checking that the payload makes sense for
+/// the type.
+pub fn frame(t: u8, payload: &[u8]) -> Vec<u8> {
+    let len = (payload.len() + 5) as u16;
+    let mut out = Vec::with_capacity(len as usize);
+    out.push(t);
+
+    out.extend(len.to_be_bytes());
+    out.extend(payload);
+
+    let cksum = Message::cksum(&out);
+    out.extend(cksum.to_be_bytes());
+    log::trace!("write: {out:?}");
+
+    out
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Message {
     Ping {
         seq_num: u8,
@@ -224,23 +262,9 @@ impl Message {
     }
 
     pub fn encode(self) -> Vec<u8> {
-        let t = self.get_type();
         // type == Byte.MIN_VALUE is special-cased! otherwise, we get the payload and then frame
         // the command. (See Codec.java)
-
-        let payload = self.get_payload();
-        let len = (payload.len() + 5) as u16;
-        let mut out = Vec::with_capacity(len as usize);
-        out.push(t);
-
-        out.extend(len.to_be_bytes());
-        out.extend(payload);
-
-        let cksum = Self::cksum(&out);
-        out.extend(cksum.to_be_bytes());
-        eprintln!("{out:?}");
-
-        out
+        frame(self.get_type(), &self.get_payload())
     }
 
     fn cksum(xs: &[u8]) -> u16 {
@@ -262,12 +286,53 @@ impl Message {
         sum
     }
 
+    /// Whether this is the sign's acknowledgement of `sent`.
+    pub fn is_ack_for(&self, sent: &Message) -> bool {
+        use Message::*;
+
+        match (sent, self) {
+            (ContentMsg { content_id: a, .. }, AckContent { content_id: b, .. })
+            | (ContentDelete { content_id: a }, AckContentDelete { content_id: b, .. })
+            | (ContentCount { content_id: a }, AckContentCount { content_id: b, .. })
+            | (ContentSchedule { content_id: a, .. }, AckContentSchedule { content_id: b, .. }) => {
+                a == b
+            }
+            (GetCfgParam { param: a }, AckGetCfgParam { param: b, .. })
+            | (SetCfgParam { param: a, .. }, AckSetCfgParam { param: b, .. })
+            | (StopCfg { stop_id: a, .. }, AckStopCfg { stop_id: b, .. })
+            | (SyncClock { seq_num: a, .. }, AckSyncClock { mark_id: b, .. })
+            | (MarkClock { sequence: a }, AckMarkClock { seq_num: b }) => a == b,
+            (ResetCfgParams, AckResetCfgParams) | (ClearStopCfg, AckClearStopCfg) => true,
+            _ => false,
+        }
+    }
+
+    /// The error code carried by an acknowledgement, zero meaning success. Acknowledgements
+    /// without an error field always succeed. `None` if this isn't an acknowledgement.
+    pub fn ack_error(&self) -> Option<u8> {
+        use Message::*;
+
+        match self {
+            AckContent { error, .. }
+            | AckContentDelete { error, .. }
+            | AckContentCount { error, .. }
+            | AckContentSchedule { error, .. }
+            | AckSyncClock { error, .. }
+            | AckGetCfgParam { error, .. }
+            | AckSetCfgParam { error, .. }
+            | AckStopCfg { error, .. } => Some(*error),
+            AckMarkClock { .. } | AckResetCfgParams | AckClearStopCfg => Some(0),
+            _ => None,
+        }
+    }
+
     pub fn get_type(&self) -> u8 {
         use Message::*;
 
         match self {
             Ping { .. } => 10,
             Pong { .. } => 11,
+            AppRunning { .. } => 8,
             Reboot => 6,
             DebugMsg { .. } => 28,
             ShellCommand { .. } => 80,
@@ -296,7 +361,6 @@ impl Message {
             AckStopCfg { .. } => 15,
             ClearStopCfg => 16,
             AckClearStopCfg => 17,
-            _ => todo!(),
         }
     }
 
@@ -305,6 +369,7 @@ impl Message {
         match self {
             Ping { seq_num } => vec![*seq_num],
             Pong { seq_num } => vec![*seq_num],
+            AppRunning { seq_num, reason } => vec![*seq_num, (*reason).into()],
             Reboot => vec![],
             DebugMsg { msg } => msg.clone().into_bytes(),
             ShellCommand {
@@ -357,7 +422,7 @@ impl Message {
                 out.extend(content_id.to_be_bytes());
                 out.push(*error);
 
-                return out;
+                out
             }
             ContentDelete { content_id } => content_id.to_be_bytes().to_vec(),
             AckContentDelete { content_id, error } => {
@@ -366,7 +431,7 @@ impl Message {
                 out.extend(content_id.to_be_bytes());
                 out.push(*error);
 
-                return out;
+                out
             }
             ContentCount { content_id } => content_id.to_be_bytes().to_vec(),
             AckContentCount {
@@ -380,10 +445,10 @@ impl Message {
                 out.push(*error);
                 out.extend(data.iter().fold(vec![], |mut acc, s| {
                     acc.extend(s.to_be_bytes().to_vec());
-                    return acc;
+                    acc
                 }));
 
-                return out;
+                out
             }
             ContentSchedule {
                 content_id,
@@ -525,7 +590,6 @@ impl Message {
             AckStopCfg { stop_id, error } => vec![*stop_id, *error],
             ClearStopCfg => vec![],
             AckClearStopCfg => vec![],
-            _ => todo!(),
         }
     }
 }
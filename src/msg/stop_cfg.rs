@@ -11,24 +11,24 @@ pub fn new(payload: Vec<u8>) -> Message {
     // skip over bytes
     buf = buf[3..].to_vec();
 
-    let title = String::from_utf8(buf.drain(..title_len).collect()).unwrap_or(String::new());
-    let phoneme = String::from_utf8(buf.drain(..phoneme_len).collect()).unwrap_or(String::new());
+    let title = String::from_utf8(buf.drain(..title_len).collect()).unwrap_or_default();
+    let phoneme = String::from_utf8(buf.drain(..phoneme_len).collect()).unwrap_or_default();
 
     let zero_msg_len = buf[0] as usize;
     buf = buf[1..].to_vec();
-    let zero_msg = String::from_utf8(buf.drain(..zero_msg_len).collect()).unwrap_or(String::new());
+    let zero_msg = String::from_utf8(buf.drain(..zero_msg_len).collect()).unwrap_or_default();
 
     let tag_len = buf[0] as usize;
     buf = buf[1..].to_vec();
-    let tag = String::from_utf8(buf.drain(..tag_len).collect()).unwrap_or(String::new());
+    let tag = String::from_utf8(buf.drain(..tag_len).collect()).unwrap_or_default();
 
     let md5_len = buf[0] as usize;
     buf = buf[1..].to_vec();
-    let md5 = String::from_utf8(buf.drain(..md5_len).collect()).unwrap_or(String::new());
+    let md5 = String::from_utf8(buf.drain(..md5_len).collect()).unwrap_or_default();
 
     let url_len = buf[0] as usize;
     buf = buf[1..].to_vec();
-    let url = String::from_utf8(buf.drain(..url_len).collect()).unwrap_or(String::new());
+    let url = String::from_utf8(buf.drain(..url_len).collect()).unwrap_or_default();
 
     Message::StopCfg {
         stop_id,
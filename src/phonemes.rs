@@ -0,0 +1,448 @@
+//! Phonemes for announcements, built from a pronunciation lexicon.
+//!
+//! The lexicon is a TOML file:
+//!
+//! ```toml
+//! # Put between words' phonemes.
+//! separator = " "
+//!
+//! [words]
+//! route = "R UW1 T"
+//! downtown = "D AW1 N T AW1 N"
+//! min = "M IH1 N AH0 T S"
+//!
+//! # Whole stop names, for names that aren't said the way their words are.
+//! [stops]
+//! "Duboce & Church" = "D UW0 B OW1 S AH0 N D CH ER1 CH"
+//! ```
+//!
+//! Numbers and times are spelled out ("14" is "fourteen", "7:05" is "seven oh five") and said
+//! with the lexicon's pronunciations of those words, or built-in ARPAbet ones. Stops are sent with
+//! phonemes for their titles unless they have their own, and text content gets a `Phoneme` payload
+//! unless it has one. Anything with a word the lexicon doesn't have is left as it is.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::config::ConfigError;
+use crate::content::Content;
+use crate::msg::content::PayloadType;
+use crate::server::Server;
+use crate::stops::Stop;
+
+const ONES: [&str; 20] = [
+    "zero",
+    "one",
+    "two",
+    "three",
+    "four",
+    "five",
+    "six",
+    "seven",
+    "eight",
+    "nine",
+    "ten",
+    "eleven",
+    "twelve",
+    "thirteen",
+    "fourteen",
+    "fifteen",
+    "sixteen",
+    "seventeen",
+    "eighteen",
+    "nineteen",
+];
+
+const TENS: [&str; 10] = [
+    "", "", "twenty", "thirty", "forty", "fifty", "sixty", "seventy", "eighty", "ninety",
+];
+
+/// ARPAbet for the words numbers and times are spelled out in, from CMUdict.
+const BUILT_IN: &[(&str, &str)] = &[
+    ("zero", "Z IH1 R OW0"),
+    ("one", "W AH1 N"),
+    ("two", "T UW1"),
+    ("three", "TH R IY1"),
+    ("four", "F AO1 R"),
+    ("five", "F AY1 V"),
+    ("six", "S IH1 K S"),
+    ("seven", "S EH1 V AH0 N"),
+    ("eight", "EY1 T"),
+    ("nine", "N AY1 N"),
+    ("ten", "T EH1 N"),
+    ("eleven", "IH0 L EH1 V AH0 N"),
+    ("twelve", "T W EH1 L V"),
+    ("thirteen", "TH ER1 T IY1 N"),
+    ("fourteen", "F AO1 R T IY1 N"),
+    ("fifteen", "F IH0 F T IY1 N"),
+    ("sixteen", "S IH0 K S T IY1 N"),
+    ("seventeen", "S EH1 V AH0 N T IY1 N"),
+    ("eighteen", "EY0 T IY1 N"),
+    ("nineteen", "N AY1 N T IY1 N"),
+    ("twenty", "T W EH1 N T IY0"),
+    ("thirty", "TH ER1 D IY0"),
+    ("forty", "F AO1 R T IY0"),
+    ("fifty", "F IH1 F T IY0"),
+    ("sixty", "S IH1 K S T IY0"),
+    ("seventy", "S EH1 V AH0 N T IY0"),
+    ("eighty", "EY1 T IY0"),
+    ("ninety", "N AY1 N T IY0"),
+    ("hundred", "HH AH1 N D R AH0 D"),
+    ("thousand", "TH AW1 Z AH0 N D"),
+    ("oh", "OW1"),
+    ("o'clock", "AH0 K L AA1 K"),
+    ("am", "EY1 EH1 M"),
+    ("pm", "P IY1 EH1 M"),
+];
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LexiconFile {
+    #[serde(default = "default_separator")]
+    separator: String,
+    #[serde(default)]
+    words: BTreeMap<String, String>,
+    #[serde(default)]
+    stops: BTreeMap<String, String>,
+}
+
+fn default_separator() -> String {
+    " ".to_string()
+}
+
+pub struct Lexicon {
+    words: HashMap<String, String>,
+    /// Stop names as words, longest first, with their phonemes.
+    stops: Vec<(Vec<String>, String)>,
+    separator: String,
+}
+
+impl Lexicon {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let file: LexiconFile = toml::from_str(&std::fs::read_to_string(path)?)?;
+        Ok(Lexicon::from(file))
+    }
+
+    /// Phonemes for some text, or the words in it there's no saying.
+    pub fn phonemes(&self, text: &str) -> Result<String, Vec<String>> {
+        let tokens = tokens(text);
+        let mut phonemes = Vec::new();
+        let mut unknown = Vec::new();
+
+        let mut i = 0;
+        while i < tokens.len() {
+            if let Some((name, stop)) = self.stops.iter().find(|(n, _)| tokens[i..].starts_with(n))
+            {
+                phonemes.push(stop.as_str());
+                i += name.len();
+                continue;
+            }
+
+            for word in spoken(&tokens[i]) {
+                match self.word(&word) {
+                    Some(p) => phonemes.push(p),
+                    None => unknown.push(word),
+                }
+            }
+            i += 1;
+        }
+
+        match unknown.is_empty() {
+            true => Ok(phonemes.join(&self.separator)),
+            false => Err(unknown),
+        }
+    }
+
+    fn word(&self, word: &str) -> Option<&str> {
+        self.words.get(word).map(String::as_str).or_else(|| {
+            BUILT_IN
+                .iter()
+                .find(|(w, _)| *w == word)
+                .map(|(_, phonemes)| *phonemes)
+        })
+    }
+}
+
+impl From<LexiconFile> for Lexicon {
+    fn from(file: LexiconFile) -> Self {
+        let mut stops: Vec<_> = file
+            .stops
+            .into_iter()
+            .map(|(name, phonemes)| (tokens(&name), phonemes))
+            .filter(|(name, _)| !name.is_empty())
+            .collect();
+        stops.sort_by_key(|(name, _)| Reverse(name.len()));
+
+        Lexicon {
+            words: file
+                .words
+                .into_iter()
+                .map(|(word, phonemes)| (word.to_lowercase(), phonemes))
+                .collect(),
+            stops,
+            separator: file.separator,
+        }
+    }
+}
+
+/// Lower-cased words, numbers and times, without punctuation. Letters and digits run together,
+/// as in "14min", are taken apart.
+fn tokens(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+
+    let chunks = text
+        .split(|c: char| !(c.is_alphanumeric() || c == ':' || c == '\''))
+        .filter(|c| !c.is_empty());
+    for chunk in chunks {
+        let mut run = String::new();
+        for c in chunk.to_lowercase().chars() {
+            let numeric = |c: char| c.is_ascii_digit() || c == ':';
+            if run
+                .chars()
+                .next_back()
+                .is_some_and(|last| numeric(last) != numeric(c))
+            {
+                tokens.extend(split_run(&std::mem::take(&mut run)));
+            }
+            run.push(c);
+        }
+        tokens.extend(split_run(&run));
+    }
+
+    tokens
+}
+
+/// A run of digits and colons is a time or numbers; anything else is a word.
+fn split_run(run: &str) -> Vec<String> {
+    let run = run.trim_matches('\'');
+    if time(run).is_some() || !run.contains(':') {
+        return match run {
+            "" => vec![],
+            run => vec![run.to_string()],
+        };
+    }
+    run.split(':')
+        .filter(|n| !n.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn time(token: &str) -> Option<(u32, u32)> {
+    let (h, m) = token.split_once(':')?;
+    if !(1..=2).contains(&h.len()) || m.len() != 2 {
+        return None;
+    }
+    let (h, m) = (h.parse().ok()?, m.parse().ok()?);
+    (h < 24 && m < 60).then_some((h, m))
+}
+
+/// The words a token is said as.
+fn spoken(token: &str) -> Vec<String> {
+    let words = if let Some((h, m)) = time(token) {
+        let mut words = number(h);
+        match m {
+            0 => words.push("o'clock"),
+            1..10 => words.extend(["oh", ONES[m as usize]]),
+            _ => words.extend(number(m)),
+        }
+        words
+    } else if token.chars().all(|c| c.is_ascii_digit()) {
+        match token.parse() {
+            // Leading zeros and long numbers are read out digit by digit.
+            Ok(n) if n < 1_000_000 && (n == 0 || !token.starts_with('0')) => number(n),
+            _ => token
+                .chars()
+                .map(|d| ONES[d.to_digit(10).unwrap_or_default() as usize])
+                .collect(),
+        }
+    } else {
+        return vec![token.to_string()];
+    };
+
+    words.into_iter().map(str::to_string).collect()
+}
+
+fn number(n: u32) -> Vec<&'static str> {
+    let mut words = Vec::new();
+    let (thousands, hundreds, rest) = (n / 1000, n / 100 % 10, n % 100);
+
+    if thousands > 0 {
+        words.extend(number(thousands));
+        words.push("thousand");
+    }
+    if hundreds > 0 {
+        words.extend([ONES[hundreds as usize], "hundred"]);
+    }
+    match rest {
+        0 if !words.is_empty() => {}
+        0..20 => words.push(ONES[rest as usize]),
+        _ => {
+            words.push(TENS[(rest / 10) as usize]);
+            if rest % 10 > 0 {
+                words.push(ONES[(rest % 10) as usize]);
+            }
+        }
+    }
+
+    words
+}
+
+/// Phonemes for a stop's title, if it has none of its own. They're made whenever the stop is sent
+/// rather than kept with it, so they follow changes to the title.
+pub fn for_stop(server: &Server, stop: &Stop) -> Result<Option<String>, String> {
+    let Some(lexicon) = &server.lexicon else {
+        return Ok(None);
+    };
+    if !stop.phoneme.is_empty() {
+        return Ok(None);
+    }
+
+    match lexicon.phonemes(&stop.title) {
+        Ok(phonemes) if phonemes.len() <= u8::MAX.into() => Ok(Some(phonemes)),
+        Ok(_) => Err(format!("Phonemes for stop {} are too long.", stop.stop_id)),
+        Err(unknown) => Err(format!(
+            "No phonemes for stop {}: {unknown:?}",
+            stop.stop_id
+        )),
+    }
+}
+
+/// Give an item a `Phoneme` payload for its text, if it has none and there's room for one.
+pub fn fill_content(server: &Server, content: &mut Content) {
+    let Some(lexicon) = &server.lexicon else {
+        return;
+    };
+    if content
+        .payloads
+        .iter()
+        .any(|(kind, _)| *kind == PayloadType::Phoneme)
+    {
+        return;
+    }
+
+    let text: Vec<_> = content
+        .payloads
+        .iter()
+        .filter(|(kind, _)| *kind == PayloadType::Msg)
+        .map(|(_, text)| String::from_utf8_lossy(text))
+        .collect();
+    match lexicon.phonemes(&text.join("\n")) {
+        Ok(phonemes) if phonemes.is_empty() => {}
+        Ok(phonemes) => {
+            content
+                .payloads
+                .push((PayloadType::Phoneme, phonemes.into_bytes()));
+            if let Err(e) = content.check() {
+                log::warn!(
+                    "No room for phonemes in content {}: {e}",
+                    content.content_id
+                );
+                content.payloads.pop();
+            }
+        }
+        Err(unknown) => log::warn!(
+            "No phonemes for content {}: {unknown:?}",
+            content.content_id
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn lexicon() -> Lexicon {
+        let file: LexiconFile = toml::from_str(
+            r#"
+            separator = " | "
+
+            [words]
+            Route = "R UW1 T"
+            min = "M IH1 N AH0 T S"
+
+            [stops]
+            "Duboce & Church" = "D UW0 B OW1 S AH0 N D CH ER1 CH"
+            "#,
+        )
+        .unwrap();
+        Lexicon::from(file)
+    }
+
+    fn said(token: &str) -> String {
+        spoken(token).join(" ")
+    }
+
+    #[test]
+    fn tokens_split_words_numbers_and_times() {
+        assert_eq!(
+            tokens("Route 14min to Duboce & Church, 7:05 'til 12:3:4"),
+            [
+                "route", "14", "min", "to", "duboce", "church", "7:05", "til", "12", "3", "4"
+            ]
+        );
+        assert_eq!(tokens("Don't"), ["don't"]);
+    }
+
+    #[test]
+    fn spells_numbers_and_times() {
+        assert_eq!(said("0"), "zero");
+        assert_eq!(said("14"), "fourteen");
+        assert_eq!(said("45"), "forty five");
+        assert_eq!(said("1205"), "one thousand two hundred five");
+        assert_eq!(said("007"), "zero zero seven");
+        assert_eq!(said("7:05"), "seven oh five");
+        assert_eq!(said("14:00"), "fourteen o'clock");
+        assert_eq!(said("14:30"), "fourteen thirty");
+    }
+
+    #[test]
+    fn phonemes() {
+        let lexicon = lexicon();
+        assert_eq!(
+            lexicon.phonemes("Route 4: 14min"),
+            Ok("R UW1 T | F AO1 R | F AO1 R T IY1 N | M IH1 N AH0 T S".to_string())
+        );
+        // Stop names are said as a whole.
+        assert_eq!(
+            lexicon.phonemes("duboce & church"),
+            Ok("D UW0 B OW1 S AH0 N D CH ER1 CH".to_string())
+        );
+        assert_eq!(
+            lexicon.phonemes("Route 4 to Duboce"),
+            Err(vec!["to".to_string(), "duboce".to_string()])
+        );
+    }
+
+    #[test]
+    fn fills_content() {
+        let mut server = Server::new(toml::from_str::<Config>("").unwrap()).unwrap();
+        server.lexicon = Some(lexicon());
+        let filled = |text: &str| {
+            let mut content = Content::text(1, text);
+            fill_content(&server, &mut content);
+            content.payloads
+        };
+
+        assert_eq!(
+            filled("4 min"),
+            [
+                (PayloadType::Msg, b"4 min".to_vec()),
+                (PayloadType::Phoneme, b"F AO1 R | M IH1 N AH0 T S".to_vec()),
+            ]
+        );
+        // A word the lexicon doesn't have.
+        assert_eq!(filled("4 min late").len(), 1);
+        // No room for phonemes.
+        assert_eq!(filled(&"4 ".repeat(20_000)).len(), 1);
+
+        let mut content = Content::text(1, "4 min");
+        content.payloads.push((PayloadType::Phoneme, b"F".to_vec()));
+        fill_content(&server, &mut content);
+        assert_eq!(content.payloads[1], (PayloadType::Phoneme, b"F".to_vec()));
+        assert_eq!(content.payloads.len(), 2);
+    }
+}
@@ -0,0 +1,295 @@
+//! GTFS-realtime `TripUpdates` feeds.
+//!
+//! Only the parts of the protobuf schema needed for arrival times are declared here, which
+//! protobuf's handling of unknown fields allows. Arrivals are taken from each stop time update's
+//! absolute arrival time, or its departure time if it has none; updates that only give a delay
+//! can't be placed without the static schedule's times, and are skipped along with skipped stops.
+//!
+//! Feeds name routes and trips by id. With a static GTFS feed to go by, routes get their names,
+//! arrivals get their trips' headsigns, and arrivals at a platform count as arrivals at its
+//! station too.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use chrono::DateTime;
+use prost::Message;
+
+use super::{Arrival, PredictionError, Source, fetch};
+use crate::gtfs::{Feed, Trip};
+
+/// `StopTimeUpdate.ScheduleRelationship.SKIPPED`.
+const SKIPPED: i32 = 1;
+
+#[derive(Clone, PartialEq, Message)]
+pub struct FeedMessage {
+    #[prost(message, required, tag = "1")]
+    pub header: FeedHeader,
+    #[prost(message, repeated, tag = "2")]
+    pub entity: Vec<FeedEntity>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct FeedHeader {
+    #[prost(string, required, tag = "1")]
+    pub gtfs_realtime_version: String,
+    #[prost(uint64, optional, tag = "3")]
+    pub timestamp: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct FeedEntity {
+    #[prost(string, required, tag = "1")]
+    pub id: String,
+    #[prost(message, optional, tag = "3")]
+    pub trip_update: Option<TripUpdate>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TripUpdate {
+    #[prost(message, required, tag = "1")]
+    pub trip: TripDescriptor,
+    #[prost(message, repeated, tag = "2")]
+    pub stop_time_update: Vec<StopTimeUpdate>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TripDescriptor {
+    #[prost(string, optional, tag = "1")]
+    pub trip_id: Option<String>,
+    #[prost(string, optional, tag = "5")]
+    pub route_id: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct StopTimeUpdate {
+    #[prost(uint32, optional, tag = "1")]
+    pub stop_sequence: Option<u32>,
+    #[prost(message, optional, tag = "2")]
+    pub arrival: Option<StopTimeEvent>,
+    #[prost(message, optional, tag = "3")]
+    pub departure: Option<StopTimeEvent>,
+    #[prost(string, optional, tag = "4")]
+    pub stop_id: Option<String>,
+    #[prost(int32, optional, tag = "5")]
+    pub schedule_relationship: Option<i32>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct StopTimeEvent {
+    #[prost(int32, optional, tag = "1")]
+    pub delay: Option<i32>,
+    #[prost(int64, optional, tag = "2")]
+    pub time: Option<i64>,
+}
+
+pub struct GtfsRt {
+    feed: String,
+    routes: HashMap<String, String>,
+    trips: HashMap<String, Trip>,
+    /// The station each platform is part of.
+    stations: HashMap<String, String>,
+}
+
+impl GtfsRt {
+    /// Poll `feed`, a URL or file, naming things from `static_feed` if it's given.
+    pub fn new(feed: String, static_feed: Option<&Path>) -> Result<Self, PredictionError> {
+        let Feed {
+            stops,
+            routes,
+            trips,
+        } = match static_feed {
+            Some(path) => Feed::open(path)?,
+            None => Feed::default(),
+        };
+        let stations = stops
+            .into_iter()
+            .filter_map(|(id, stop)| Some((id, stop.parent?)))
+            .collect();
+
+        Ok(GtfsRt {
+            feed,
+            routes,
+            trips,
+            stations,
+        })
+    }
+
+    /// Every arrival in a feed, by stop id.
+    pub fn arrivals(&self, msg: &FeedMessage) -> BTreeMap<String, Vec<Arrival>> {
+        let mut arrivals: BTreeMap<String, Vec<Arrival>> = BTreeMap::new();
+
+        for update in msg.entity.iter().filter_map(|e| e.trip_update.as_ref()) {
+            let trip = update
+                .trip
+                .trip_id
+                .as_ref()
+                .and_then(|id| self.trips.get(id));
+            let route_id = update.trip.route_id.as_deref().unwrap_or_default();
+            let route = match trip {
+                Some(trip) => trip.route.clone(),
+                None => self
+                    .routes
+                    .get(route_id)
+                    .cloned()
+                    .unwrap_or(route_id.to_string()),
+            };
+            let headsign = trip.map(|t| t.headsign.clone()).unwrap_or_default();
+
+            for stop_time in &update.stop_time_update {
+                if stop_time.schedule_relationship == Some(SKIPPED) {
+                    continue;
+                }
+                let (Some(stop_id), Some(time)) = (&stop_time.stop_id, event_time(stop_time))
+                else {
+                    continue;
+                };
+                let Some(at) = DateTime::from_timestamp(time, 0) else {
+                    continue;
+                };
+
+                let arrival = Arrival {
+                    route: route.clone(),
+                    headsign: headsign.clone(),
+                    at,
+                };
+                if let Some(station) = self.stations.get(stop_id) {
+                    arrivals
+                        .entry(station.clone())
+                        .or_default()
+                        .push(arrival.clone());
+                }
+                arrivals.entry(stop_id.clone()).or_default().push(arrival);
+            }
+        }
+
+        arrivals
+    }
+}
+
+fn event_time(stop_time: &StopTimeUpdate) -> Option<i64> {
+    [&stop_time.arrival, &stop_time.departure]
+        .into_iter()
+        .flatten()
+        .find_map(|e| e.time)
+}
+
+impl Source for GtfsRt {
+    fn poll(&mut self) -> Result<BTreeMap<String, Vec<Arrival>>, PredictionError> {
+        let msg = FeedMessage::decode(fetch(&self.feed)?.as_slice())
+            .map_err(|e| PredictionError::Decode(e.to_string()))?;
+        Ok(self.arrivals(&msg))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stop_time(stop_id: &str, arrival: Option<i64>, departure: Option<i64>) -> StopTimeUpdate {
+        let event = |time| StopTimeEvent {
+            delay: None,
+            time: Some(time),
+        };
+        StopTimeUpdate {
+            stop_sequence: None,
+            arrival: arrival.map(event),
+            departure: departure.map(event),
+            stop_id: Some(stop_id.to_string()),
+            schedule_relationship: None,
+        }
+    }
+
+    fn trip_update(trip_id: &str, route_id: &str, stop_times: Vec<StopTimeUpdate>) -> FeedEntity {
+        FeedEntity {
+            id: trip_id.to_string(),
+            trip_update: Some(TripUpdate {
+                trip: TripDescriptor {
+                    trip_id: Some(trip_id.to_string()),
+                    route_id: Some(route_id.to_string()),
+                },
+                stop_time_update: stop_times,
+            }),
+        }
+    }
+
+    /// A feed as it would come over the wire.
+    fn encoded() -> Vec<u8> {
+        let skipped = StopTimeUpdate {
+            schedule_relationship: Some(SKIPPED),
+            ..stop_time("ST2", Some(1_700_000_300), None)
+        };
+        let delay_only = StopTimeUpdate {
+            arrival: Some(StopTimeEvent {
+                delay: Some(60),
+                time: None,
+            }),
+            ..stop_time("ST2", None, None)
+        };
+        FeedMessage {
+            header: FeedHeader {
+                gtfs_realtime_version: "2.0".to_string(),
+                timestamp: Some(1_700_000_000),
+            },
+            entity: vec![
+                trip_update(
+                    "T1",
+                    "R1",
+                    vec![
+                        stop_time("ST1", Some(1_700_000_060), Some(1_700_000_090)),
+                        skipped,
+                        delay_only,
+                    ],
+                ),
+                // Neither the trip nor the route is in the static feed, nor is the stop.
+                trip_update("T9", "R9", vec![stop_time("X9", None, Some(1_700_000_120))]),
+            ],
+        }
+        .encode_to_vec()
+    }
+
+    fn gtfs_rt() -> GtfsRt {
+        let map = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        GtfsRt {
+            feed: String::new(),
+            routes: map(&[("R1", "Red")]),
+            trips: [(
+                "T1".to_string(),
+                Trip {
+                    route: "Red".to_string(),
+                    headsign: "Airport".to_string(),
+                },
+            )]
+            .into(),
+            stations: map(&[("ST1", "ST"), ("ST2", "ST")]),
+        }
+    }
+
+    fn arrival(route: &str, headsign: &str, time: i64) -> Arrival {
+        Arrival {
+            route: route.to_string(),
+            headsign: headsign.to_string(),
+            at: DateTime::from_timestamp(time, 0).unwrap(),
+        }
+    }
+
+    #[test]
+    fn arrivals() {
+        let msg = FeedMessage::decode(encoded().as_slice()).unwrap();
+        let arrivals = gtfs_rt().arrivals(&msg);
+
+        assert_eq!(arrivals["ST1"], [arrival("Red", "Airport", 1_700_000_060)]);
+        // The station has its platforms' arrivals.
+        assert_eq!(arrivals["ST"], arrivals["ST1"]);
+        // Skipped, and only a delay to go by.
+        assert!(!arrivals.contains_key("ST2"));
+        // Unknown to the static feed, named by id and timed by its departure.
+        assert_eq!(arrivals["X9"], [arrival("R9", "", 1_700_000_120)]);
+        assert_eq!(arrivals.len(), 3);
+    }
+}
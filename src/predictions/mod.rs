@@ -0,0 +1,172 @@
+//! Arrival predictions from realtime feeds, shown on signs as countdowns.
+//!
+//! Each configured source is polled on its own thread. Its arrivals are matched to signs through
+//! a stop map (see [`crate::stop_map`]), and each mapped stop gets a content item on its sign,
+//! named `arrivals-<stop_id>`, laid out as configured for the sign (see [`template`]). An item is
+//! only sent again when its text changes, or if the sign didn't take it last time.
+
+pub mod gtfs_rt;
+pub mod nextbus;
+pub mod siri;
+pub mod template;
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use thiserror::Error;
+
+use crate::config::{ConfigError, PredictionConfig};
+use crate::content::{self, Content};
+use crate::gtfs::GtfsError;
+use crate::hub::Outcome;
+use crate::phonemes;
+use crate::registry::SignId;
+use crate::server::Server;
+use crate::stop_map::StopMap;
+
+#[derive(Error, Debug)]
+pub enum PredictionError {
+    #[error("Failed i/o: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed request: {0}")]
+    Http(#[from] ureq::Error),
+    #[error("Invalid feed: {0}")]
+    Decode(String),
+    #[error("Couldn't read static GTFS: {0}")]
+    Gtfs(#[from] GtfsError),
+    #[error("Couldn't load stop map: {0}")]
+    StopMap(#[from] ConfigError),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Arrival {
+    pub route: String,
+    pub headsign: String,
+    pub at: DateTime<Utc>,
+}
+
+/// Somewhere predictions come from.
+pub trait Source: Send {
+    /// Upcoming arrivals, by the feed's stop id.
+    fn poll(&mut self) -> Result<BTreeMap<String, Vec<Arrival>>, PredictionError>;
+}
+
+/// Start polling every configured source.
+pub fn start(server: &Arc<Server>) -> Result<(), PredictionError> {
+    for config in &server.config.predictions {
+        let map = StopMap::load(config.stop_map())?;
+        let source: Box<dyn Source> = match config {
+            PredictionConfig::GtfsRt {
+                feed, static_feed, ..
+            } => Box::new(gtfs_rt::GtfsRt::new(feed.clone(), static_feed.as_deref())?),
+            PredictionConfig::NextBus {
+                base_url,
+                agency,
+                stops,
+                ..
+            } => Box::new(nextbus::NextBus {
+                base_url: base_url.clone(),
+                agency: agency.clone(),
+                stops: stops.clone(),
+            }),
+            PredictionConfig::Siri {
+                feed,
+                monitoring_refs,
+                requestor_ref,
+                time_zone,
+                ..
+            } => Box::new(siri::Siri {
+                feed: feed.clone(),
+                monitoring_refs: monitoring_refs.clone(),
+                requestor_ref: requestor_ref
+                    .clone()
+                    .unwrap_or("nextbus-sign-server".to_string()),
+                zone: time_zone.or(server.config.time_zone),
+            }),
+        };
+        let interval = Duration::from_secs(config.interval_secs());
+
+        let server = server.clone();
+        thread::spawn(move || run(&server, source, &map, interval));
+    }
+    Ok(())
+}
+
+fn run(server: &Server, mut source: Box<dyn Source>, map: &StopMap, interval: Duration) {
+    let mut shown: BTreeMap<(SignId, u8), String> = BTreeMap::new();
+
+    loop {
+        match source.poll() {
+            Ok(arrivals) => update(server, map, &arrivals, &mut shown),
+            Err(e) => log::warn!("Couldn't get predictions: {e}"),
+        }
+        thread::sleep(interval);
+    }
+}
+
+/// Show each mapped stop's arrivals on its sign, if they've changed since they were last shown.
+fn update(
+    server: &Server,
+    map: &StopMap,
+    arrivals: &BTreeMap<String, Vec<Arrival>>,
+    shown: &mut BTreeMap<(SignId, u8), String>,
+) {
+    let now = Utc::now();
+
+    for mapped in &map.stops {
+        let stops = server.store.get(&mapped.sign).stops.unwrap_or_default();
+        let zero_msg = stops
+            .iter()
+            .find(|s| s.stop_id == mapped.stop_id)
+            .map_or("", |s| s.zero_countdown_msg.as_str());
+        let text = server.config.arrivals(&mapped.sign).render(
+            arrivals.get(&mapped.feed_stop).map_or(&[], Vec::as_slice),
+            now,
+            server.config.time_zone(&mapped.sign),
+            zero_msg,
+        );
+
+        let key = (mapped.sign.clone(), mapped.stop_id);
+        if shown.get(&key) == Some(&text) {
+            continue;
+        }
+
+        let name = format!("arrivals-{}", mapped.stop_id);
+        let make = |id| {
+            let mut content = Content::text(id, &text);
+            phonemes::fill_content(server, &mut content);
+            content
+        };
+        match content::set_named(server, &mapped.sign, &name, make) {
+            // Anything short of the sign taking it is tried again next time.
+            Some((_, deliveries))
+                if deliveries
+                    .iter()
+                    .any(|d| matches!(d.outcome, Outcome::Acked { error: 0 })) =>
+            {
+                shown.insert(key, text);
+            }
+            Some(_) => {}
+            None => log::warn!("{} has no content ids left.", mapped.sign),
+        }
+    }
+}
+
+/// Read a feed from a URL, or from a file so it can be tried offline.
+pub fn fetch(location: &str) -> Result<Vec<u8>, PredictionError> {
+    if !(location.starts_with("http://") || location.starts_with("https://")) {
+        return Ok(std::fs::read(location)?);
+    }
+
+    let mut body = Vec::new();
+    ureq::get(location)
+        .call()?
+        .into_body()
+        .into_reader()
+        .read_to_end(&mut body)?;
+    Ok(body)
+}
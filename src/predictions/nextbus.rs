@@ -0,0 +1,229 @@
+//! NextBus `predictions` XML feeds, as still published through umo and compatible services.
+//!
+//! All of a source's stops are asked for at once with `predictionsForMultiStops`:
+//!
+//! ```xml
+//! <body>
+//!   <predictions routeTag="N" stopTag="5205" stopTitle="Duboce St &amp; Church St">
+//!     <direction title="Outbound to Ocean Beach">
+//!       <prediction epochTime="1760817600000" minutes="4" tripTag="11228853"/>
+//!     </direction>
+//!   </predictions>
+//! </body>
+//! ```
+//!
+//! Only the configured route and stop pairs are kept, since a feed may answer with other routes
+//! at the same stops. Arrivals are keyed by stop tag, named by route tag, and headed for the
+//! direction's title.
+
+use std::collections::BTreeMap;
+
+use chrono::DateTime;
+
+use super::{Arrival, PredictionError, Source};
+use crate::config::RouteStop;
+
+pub struct NextBus {
+    pub base_url: String,
+    pub agency: String,
+    pub stops: Vec<RouteStop>,
+}
+
+impl Source for NextBus {
+    fn poll(&mut self) -> Result<BTreeMap<String, Vec<Arrival>>, PredictionError> {
+        let mut request = ureq::get(&self.base_url)
+            .query("command", "predictionsForMultiStops")
+            .query("a", &self.agency);
+        for RouteStop { route, stop } in &self.stops {
+            request = request.query("stops", format!("{route}|{stop}"));
+        }
+
+        let xml = request.call()?.body_mut().read_to_string()?;
+        parse(&xml, &self.stops)
+    }
+}
+
+/// The arrivals in a `predictions` response at the given route and stop pairs, by stop tag.
+pub fn parse(
+    xml: &str,
+    stops: &[RouteStop],
+) -> Result<BTreeMap<String, Vec<Arrival>>, PredictionError> {
+    let doc =
+        roxmltree::Document::parse(xml).map_err(|e| PredictionError::Decode(e.to_string()))?;
+    let body = doc.root_element();
+
+    if let Some(error) = body.children().find(|n| n.has_tag_name("Error")) {
+        return Err(PredictionError::Decode(format!(
+            "feed error: {}",
+            error.text().unwrap_or_default().trim()
+        )));
+    }
+
+    let mut arrivals: BTreeMap<String, Vec<Arrival>> = BTreeMap::new();
+    for predictions in body.children().filter(|n| n.has_tag_name("predictions")) {
+        let (Some(route), Some(stop)) = (
+            predictions.attribute("routeTag"),
+            predictions.attribute("stopTag"),
+        ) else {
+            continue;
+        };
+        if !stops.iter().any(|s| s.route == route && s.stop == stop) {
+            continue;
+        }
+        let stop_arrivals = arrivals.entry(stop.to_string()).or_default();
+
+        for direction in predictions
+            .children()
+            .filter(|n| n.has_tag_name("direction"))
+        {
+            let headsign = direction.attribute("title").unwrap_or_default();
+
+            for prediction in direction
+                .children()
+                .filter(|n| n.has_tag_name("prediction"))
+            {
+                let Some(at) = prediction
+                    .attribute("epochTime")
+                    .and_then(|t| t.parse().ok())
+                    .and_then(DateTime::from_timestamp_millis)
+                else {
+                    continue;
+                };
+
+                stop_arrivals.push(Arrival {
+                    route: route.to_string(),
+                    headsign: headsign.to_string(),
+                    at,
+                });
+            }
+        }
+    }
+
+    Ok(arrivals)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+
+    fn route_stop(route: &str, stop: &str) -> RouteStop {
+        RouteStop {
+            route: route.to_string(),
+            stop: stop.to_string(),
+        }
+    }
+
+    fn parse_fixture(stops: &[RouteStop]) -> BTreeMap<String, Vec<Arrival>> {
+        parse(include_str!("fixtures/nextbus.xml"), stops).unwrap()
+    }
+
+    #[test]
+    fn parses_every_direction() {
+        let arrivals = parse_fixture(&[route_stop("N", "5205")]);
+        let at = |ms| DateTime::from_timestamp_millis(ms).unwrap();
+        assert_eq!(
+            arrivals["5205"],
+            [
+                Arrival {
+                    route: "N".to_string(),
+                    headsign: "Outbound to Ocean Beach".to_string(),
+                    at: at(1760817600000),
+                },
+                Arrival {
+                    route: "N".to_string(),
+                    headsign: "Outbound to Ocean Beach".to_string(),
+                    at: at(1760818200000),
+                },
+                Arrival {
+                    route: "N".to_string(),
+                    headsign: "Inbound to Caltrain".to_string(),
+                    at: at(1760817900000),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn matches_route_as_well_as_stop() {
+        let arrivals = parse_fixture(&[route_stop("J", "5205"), route_stop("J", "4448")]);
+        let routes: Vec<_> = arrivals["5205"].iter().map(|a| a.route.as_str()).collect();
+        assert_eq!(routes, ["J"]);
+        assert!(!arrivals.contains_key("4448"));
+
+        // Configured, but with nothing coming.
+        let arrivals = parse_fixture(&[route_stop("L", "5205")]);
+        assert_eq!(arrivals["5205"], []);
+    }
+
+    #[test]
+    fn skips_bad_times() {
+        let arrivals = parse_fixture(&[route_stop("N", "4448")]);
+        assert_eq!(arrivals["4448"].len(), 1);
+    }
+
+    #[test]
+    fn feed_errors() {
+        let xml = r#"<body><Error shouldRetry="false">agency parameter "a=bogus" is not valid.</Error></body>"#;
+        assert!(matches!(
+            parse(xml, &[]),
+            Err(PredictionError::Decode(e)) if e.contains("bogus")
+        ));
+    }
+
+    #[test]
+    fn polls_over_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = String::new();
+            let mut reader = BufReader::new(&stream);
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                request += &line;
+            }
+
+            let body = include_str!("fixtures/nextbus.xml");
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: text/xml\r\nContent-Length: {}\r\n\
+                 Connection: close\r\n\r\n{body}",
+                body.len()
+            )
+            .unwrap();
+            request
+        });
+
+        let mut source = NextBus {
+            base_url: format!("http://{addr}/service/publicXMLFeed"),
+            agency: "sf-muni".to_string(),
+            stops: vec![route_stop("N", "5205"), route_stop("N", "4448")],
+        };
+        let arrivals = source.poll().unwrap();
+        assert_eq!(arrivals["5205"].len(), 3);
+        assert_eq!(arrivals["4448"].len(), 1);
+
+        let request = server.join().unwrap();
+        let target = request.lines().next().unwrap();
+        assert!(
+            target.starts_with("GET /service/publicXMLFeed?"),
+            "{target}"
+        );
+        for query in [
+            "command=predictionsForMultiStops",
+            "a=sf-muni",
+            "stops=N%7C5205",
+            "stops=N%7C4448",
+        ] {
+            assert!(target.contains(query), "{target} lacks {query}");
+        }
+    }
+}
@@ -0,0 +1,233 @@
+//! SIRI StopMonitoring deliveries.
+//!
+//! A delivery is read from a file, fetched from a URL, or, given the stops to monitor, asked for
+//! with a `StopMonitoringRequest`. Each `MonitoredStopVisit` is an arrival at its
+//! `MonitoringRef`, on its published line name (or line ref), headed for its destination, at its
+//! call's expected arrival time. Calls without one fall back to the expected departure time, then
+//! to the aimed times. Times without an offset are taken to be in the feed's zone, or the server's
+//! local time if it has none.
+
+use std::collections::BTreeMap;
+
+use chrono::Utc;
+use chrono_tz::Tz;
+use roxmltree::Node;
+
+use super::{Arrival, PredictionError, Source, fetch};
+use crate::tz;
+
+pub struct Siri {
+    /// A URL or a file.
+    pub feed: String,
+    /// Stops to ask for. Without any, the feed is just fetched.
+    pub monitoring_refs: Vec<String>,
+    pub requestor_ref: String,
+    /// What times without an offset are in.
+    pub zone: Option<Tz>,
+}
+
+impl Siri {
+    fn request(&self) -> String {
+        let now = Utc::now().to_rfc3339();
+        let stops: String = self
+            .monitoring_refs
+            .iter()
+            .map(|r| {
+                format!(
+                    "<StopMonitoringRequest version=\"2.0\">\
+                     <RequestTimestamp>{now}</RequestTimestamp>\
+                     <MonitoringRef>{}</MonitoringRef>\
+                     </StopMonitoringRequest>",
+                    escape(r)
+                )
+            })
+            .collect();
+
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+             <Siri xmlns=\"http://www.siri.org.uk/siri\" version=\"2.0\">\
+             <ServiceRequest>\
+             <RequestTimestamp>{now}</RequestTimestamp>\
+             <RequestorRef>{}</RequestorRef>\
+             {stops}\
+             </ServiceRequest>\
+             </Siri>",
+            escape(&self.requestor_ref)
+        )
+    }
+}
+
+impl Source for Siri {
+    fn poll(&mut self) -> Result<BTreeMap<String, Vec<Arrival>>, PredictionError> {
+        let xml = if self.monitoring_refs.is_empty() {
+            String::from_utf8(fetch(&self.feed)?)
+                .map_err(|e| PredictionError::Decode(e.to_string()))?
+        } else {
+            ureq::post(&self.feed)
+                .content_type("application/xml")
+                .send(self.request())?
+                .body_mut()
+                .read_to_string()?
+        };
+        parse(&xml, self.zone)
+    }
+}
+
+/// Every arrival in a SIRI document, by monitoring ref.
+pub fn parse(
+    xml: &str,
+    zone: Option<Tz>,
+) -> Result<BTreeMap<String, Vec<Arrival>>, PredictionError> {
+    let doc =
+        roxmltree::Document::parse(xml).map_err(|e| PredictionError::Decode(e.to_string()))?;
+
+    for delivery in doc
+        .descendants()
+        .filter(|n| n.has_tag_name("StopMonitoringDelivery"))
+    {
+        if text(delivery, "Status") == Some("false") {
+            let reason = child(delivery, "ErrorCondition")
+                .and_then(|e| text(e, "Description"))
+                .unwrap_or("no reason given");
+            return Err(PredictionError::Decode(format!(
+                "delivery failed: {reason}"
+            )));
+        }
+    }
+
+    let mut arrivals: BTreeMap<String, Vec<Arrival>> = BTreeMap::new();
+    for visit in doc
+        .descendants()
+        .filter(|n| n.has_tag_name("MonitoredStopVisit"))
+    {
+        let Some(journey) = child(visit, "MonitoredVehicleJourney") else {
+            continue;
+        };
+        let call = child(journey, "MonitoredCall");
+        let Some(stop) =
+            text(visit, "MonitoringRef").or(call.and_then(|c| text(c, "StopPointRef")))
+        else {
+            continue;
+        };
+
+        let times = [
+            "ExpectedArrivalTime",
+            "ExpectedDepartureTime",
+            "AimedArrivalTime",
+            "AimedDepartureTime",
+        ];
+        let Some(at) = call.and_then(|c| {
+            times
+                .iter()
+                .filter_map(|t| text(c, t))
+                .find_map(|t| match tz::instant(t, zone) {
+                    Ok(at) => Some(at),
+                    Err(e) => {
+                        log::warn!("Skipping a time in a visit to {stop}: {e}");
+                        None
+                    }
+                })
+        }) else {
+            continue;
+        };
+
+        arrivals.entry(stop.to_string()).or_default().push(Arrival {
+            route: text(journey, "PublishedLineName")
+                .or(text(journey, "LineRef"))
+                .unwrap_or_default()
+                .to_string(),
+            headsign: text(journey, "DestinationName")
+                .unwrap_or_default()
+                .to_string(),
+            at,
+        });
+    }
+
+    Ok(arrivals)
+}
+
+fn child<'a, 'i>(node: Node<'a, 'i>, name: &str) -> Option<Node<'a, 'i>> {
+    node.children().find(|n| n.has_tag_name(name))
+}
+
+fn text<'a>(node: Node<'a, '_>, name: &str) -> Option<&'a str> {
+    child(node, name)?.text().map(str::trim)
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, TimeZone};
+
+    use super::*;
+
+    fn at(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time).unwrap().to_utc()
+    }
+
+    #[test]
+    fn parses_visits() {
+        let arrivals = parse(include_str!("fixtures/siri.xml"), Some(Tz::Europe__London)).unwrap();
+        assert_eq!(
+            arrivals["490000077E"],
+            [
+                Arrival {
+                    route: "73".to_string(),
+                    headsign: "Stoke Newington".to_string(),
+                    at: at("2026-10-18T20:05:30+01:00"),
+                },
+                // No published name, an expected time that won't parse, and an aimed time
+                // without an offset.
+                Arrival {
+                    route: "390".to_string(),
+                    headsign: "Archway".to_string(),
+                    at: at("2026-10-18T20:12:00+01:00"),
+                },
+            ]
+        );
+        // Keyed by the call's stop when there's no monitoring ref, and skipped without a time.
+        assert_eq!(
+            arrivals["490000077F"],
+            [Arrival {
+                route: "38".to_string(),
+                headsign: "Clapton Pond".to_string(),
+                at: at("2026-10-18T19:08:00Z"),
+            }]
+        );
+    }
+
+    #[test]
+    fn times_without_an_offset_are_in_the_zone() {
+        let arrivals = parse(
+            include_str!("fixtures/siri.xml"),
+            Some(Tz::America__New_York),
+        )
+        .unwrap();
+        assert_eq!(
+            arrivals["490000077E"][1].at,
+            Tz::America__New_York
+                .with_ymd_and_hms(2026, 10, 18, 20, 12, 0)
+                .unwrap()
+                .to_utc()
+        );
+    }
+
+    #[test]
+    fn failed_delivery() {
+        let xml = r#"<Siri xmlns="http://www.siri.org.uk/siri"><ServiceDelivery>
+            <StopMonitoringDelivery>
+              <Status>false</Status>
+              <ErrorCondition><Description>Unknown MonitoringRef</Description></ErrorCondition>
+            </StopMonitoringDelivery>
+          </ServiceDelivery></Siri>"#;
+        assert!(matches!(
+            parse(xml, None),
+            Err(PredictionError::Decode(e)) if e.contains("Unknown MonitoringRef")
+        ));
+    }
+}
@@ -0,0 +1,345 @@
+//! How arrivals are laid out on signs.
+//!
+//! Each arrival is a row, made from a template such as `{route:>3} {headsign:<14} {minutes}min`.
+//! The fields are `route`, `headsign`, `minutes` and `time`, the arrival's clock time (`14:05`) in
+//! the sign's time zone. A field can be given a width after a colon,
+//! aligned with `<` (the default), `>` or `^`; it's padded to that width, and cut short if it's
+//! longer. `{{` and `}}` are literal braces.
+//!
+//! When an arrival is due, `{minutes}` and the unit written straight after it (the `min` of
+//! `{minutes}min`) are replaced by the stop's `zero_countdown_msg`, if it has one, whatever the
+//! field's width.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Local, Utc};
+use chrono_tz::Tz;
+use serde::Deserialize;
+
+use super::Arrival;
+
+const DEFAULT_TEMPLATE: &str = "{route} {headsign} {minutes}min";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Route,
+    Headsign,
+    Minutes,
+    Time,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Align {
+    Left,
+    Right,
+    Center,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Field {
+        field: Field,
+        align: Align,
+        width: Option<usize>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' | '}' if chars.peek() == Some(&c) => {
+                    chars.next();
+                    text.push(c);
+                }
+                '{' => {
+                    let mut spec = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => spec.push(c),
+                            None => return Err(format!("unclosed {{ in {template:?}")),
+                        }
+                    }
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(parse_field(&spec)?);
+                }
+                '}' => return Err(format!("unmatched }} in {template:?}")),
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+
+        Ok(Template { parts })
+    }
+
+    /// One arrival's row, with its time in `zone`. `zero_msg` is shown in place of the countdown
+    /// once it reaches zero.
+    pub fn render(
+        &self,
+        arrival: &Arrival,
+        minutes: i64,
+        zone: Option<Tz>,
+        zero_msg: &str,
+    ) -> String {
+        let due = minutes <= 0 && !zero_msg.is_empty();
+        let mut row = String::new();
+        let mut skip_unit = false;
+
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => {
+                    let text = match skip_unit {
+                        true => text.trim_start_matches(|c: char| !c.is_whitespace()),
+                        false => text,
+                    };
+                    row += text;
+                    skip_unit = false;
+                }
+                Part::Field {
+                    field,
+                    align,
+                    width,
+                } => {
+                    row += &match field {
+                        Field::Route => pad(&arrival.route, *align, *width),
+                        Field::Headsign => pad(&arrival.headsign, *align, *width),
+                        Field::Minutes if due => zero_msg.to_string(),
+                        Field::Minutes => pad(&minutes.to_string(), *align, *width),
+                        Field::Time => pad(&clock_time(arrival.at, zone), *align, *width),
+                    };
+                    skip_unit = *field == Field::Minutes && due;
+                }
+            }
+        }
+
+        row
+    }
+}
+
+impl TryFrom<String> for Template {
+    type Error = String;
+
+    fn try_from(template: String) -> Result<Self, String> {
+        Template::parse(&template)
+    }
+}
+
+impl Default for Template {
+    fn default() -> Self {
+        Template::parse(DEFAULT_TEMPLATE).expect("default template is valid")
+    }
+}
+
+fn parse_field(spec: &str) -> Result<Part, String> {
+    let (name, format) = spec.split_once(':').unwrap_or((spec, ""));
+    let field = match name.trim() {
+        "route" => Field::Route,
+        "headsign" => Field::Headsign,
+        "minutes" => Field::Minutes,
+        "time" => Field::Time,
+        other => return Err(format!("unknown field {other:?}")),
+    };
+
+    let (align, width) = match format.chars().next() {
+        Some('<') => (Align::Left, &format[1..]),
+        Some('>') => (Align::Right, &format[1..]),
+        Some('^') => (Align::Center, &format[1..]),
+        _ => (Align::Left, format),
+    };
+    let width = match width {
+        "" => None,
+        width => Some(
+            width
+                .parse()
+                .map_err(|_| format!("invalid format {format:?} for {name}"))?,
+        ),
+    };
+
+    Ok(Part::Field {
+        field,
+        align,
+        width,
+    })
+}
+
+fn clock_time(at: DateTime<Utc>, zone: Option<Tz>) -> String {
+    match zone {
+        Some(zone) => at.with_timezone(&zone).format("%H:%M").to_string(),
+        None => at.with_timezone(&Local).format("%H:%M").to_string(),
+    }
+}
+
+fn pad(value: &str, align: Align, width: Option<usize>) -> String {
+    let Some(width) = width else {
+        return value.to_string();
+    };
+    let value: String = value.chars().take(width).collect();
+    match align {
+        Align::Left => format!("{value:<width$}"),
+        Align::Right => format!("{value:>width$}"),
+        Align::Center => format!("{value:^width$}"),
+    }
+}
+
+/// The order rows are shown in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Sort {
+    /// Soonest first.
+    #[default]
+    Time,
+    /// By route, then soonest first.
+    Route,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Layout {
+    pub template: Template,
+    /// Templates for particular routes, by route name.
+    pub lines: BTreeMap<String, Template>,
+    /// How many characters fit on a row. Longer rows are cut short.
+    pub width: Option<usize>,
+    pub rows: usize,
+    pub sort: Sort,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout {
+            template: Template::default(),
+            lines: BTreeMap::new(),
+            width: None,
+            rows: 3,
+            sort: Sort::Time,
+        }
+    }
+}
+
+impl Layout {
+    /// A row for each of the first few upcoming arrivals, for a sign in `zone`.
+    pub fn render(
+        &self,
+        arrivals: &[Arrival],
+        now: DateTime<Utc>,
+        zone: Option<Tz>,
+        zero_msg: &str,
+    ) -> String {
+        let mut upcoming: Vec<&Arrival> = arrivals.iter().filter(|a| a.at >= now).collect();
+        match self.sort {
+            Sort::Time => upcoming.sort_by_key(|a| a.at),
+            Sort::Route => upcoming.sort_by(|a, b| (&a.route, a.at).cmp(&(&b.route, b.at))),
+        }
+
+        upcoming
+            .iter()
+            .take(self.rows)
+            .map(|a| {
+                let template = self.lines.get(&a.route).unwrap_or(&self.template);
+                let row = template.render(a, (a.at - now).num_minutes(), zone, zero_msg);
+                match self.width {
+                    Some(width) => row.chars().take(width).collect(),
+                    None => row,
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arrival(route: &str, headsign: &str, at: &str) -> Arrival {
+        Arrival {
+            route: route.to_string(),
+            headsign: headsign.to_string(),
+            at: at.parse().unwrap(),
+        }
+    }
+
+    fn render(template: &str, minutes: i64, zero_msg: &str) -> String {
+        let arrival = arrival("N", "Ocean Beach", "2024-03-04T14:05:00Z");
+        Template::parse(template)
+            .unwrap()
+            .render(&arrival, minutes, Some(Tz::UTC), zero_msg)
+    }
+
+    #[test]
+    fn fields() {
+        assert_eq!(
+            render("{route} to {headsign} at {time}, {minutes}min", 3, ""),
+            "N to Ocean Beach at 14:05, 3min"
+        );
+        assert_eq!(render("{{{route}}}", 3, ""), "{N}");
+    }
+
+    #[test]
+    fn alignment_and_truncation() {
+        assert_eq!(render("[{route:3}]", 3, ""), "[N  ]");
+        assert_eq!(render("[{route:>3}]", 3, ""), "[  N]");
+        assert_eq!(render("[{route:^3}]", 3, ""), "[ N ]");
+        assert_eq!(render("[{headsign:<5}]", 3, ""), "[Ocean]");
+    }
+
+    #[test]
+    fn zero_countdown() {
+        // The unit goes along with the countdown, but not what follows it.
+        assert_eq!(render("{route} {minutes:>2}min !", 0, "Due"), "N Due !");
+        assert_eq!(render("{route} {minutes:>2}min", 0, ""), "N  0min");
+        assert_eq!(render("{route} {minutes:>2}min", 1, "Due"), "N  1min");
+    }
+
+    #[test]
+    fn parse_errors() {
+        for template in ["{route", "route}", "{platform}", "{route:>x}"] {
+            assert!(Template::parse(template).is_err(), "{template}");
+        }
+    }
+
+    #[test]
+    fn layout() {
+        let now = "2024-03-04T14:00:00Z".parse().unwrap();
+        let arrivals = [
+            arrival("L", "Zoo", "2024-03-04T14:07:00Z"),
+            arrival("N", "Ocean Beach", "2024-03-04T14:03:00Z"),
+            arrival("L", "Zoo", "2024-03-04T14:02:00Z"),
+            // Gone already.
+            arrival("N", "Ocean Beach", "2024-03-04T13:59:00Z"),
+        ];
+        let mut layout = Layout {
+            template: Template::parse("{route} {minutes}").unwrap(),
+            rows: 2,
+            ..Layout::default()
+        };
+        assert_eq!(layout.render(&arrivals, now, None, ""), "L 2\nN 3");
+
+        layout.sort = Sort::Route;
+        layout.rows = 3;
+        assert_eq!(layout.render(&arrivals, now, None, ""), "L 2\nL 7\nN 3");
+
+        layout.lines.insert(
+            "N".to_string(),
+            Template::parse("{headsign} {minutes}").unwrap(),
+        );
+        layout.width = Some(7);
+        assert_eq!(layout.render(&arrivals, now, None, ""), "L 2\nL 7\nOcean B");
+    }
+}
@@ -0,0 +1,217 @@
+//! A transparent relay between a sign and another server, for watching how the original vendor
+//! server talks to signs.
+//!
+//! Every frame is decoded and logged on its way through, and can be dropped, replaced or followed
+//! by an injected frame according to a list of [`Rule`]s. Frames that don't decode are passed on
+//! untouched.
+//!
+//! Rules are read from a text file with one rule per line:
+//!
+//! ```text
+//! # action   direction  type  [new type  payload hex]
+//! drop       to-sign    10
+//! replace    to-sign    26    26         2a6575de0000
+//! inject     to-server  8     28         68656c6c6f
+//! ```
+//!
+//! The direction is `to-sign`, `to-server` or `any`, and message types are the numbers used on the
+//! wire. `replace` sends the new frame instead of the matching one; `inject` sends it right after.
+//! An empty payload is written as `-`.
+
+use std::io::Write;
+use std::net::TcpStream;
+use std::panic::catch_unwind;
+use std::sync::Arc;
+use std::thread;
+
+use thiserror::Error;
+
+use crate::msg::{self, DecodeError, Message};
+use crate::record::{Direction, Recorder};
+
+#[derive(Error, Debug)]
+pub enum RuleError {
+    #[error("Line {0}: expected {1}")]
+    Syntax(usize, &'static str),
+    #[error("Line {0}: unknown action {1:?}")]
+    UnknownAction(usize, String),
+    #[error("Line {0}: unknown direction {1:?}")]
+    UnknownDirection(usize, String),
+    #[error("Line {0}: bad message type {1:?}")]
+    BadType(usize, String),
+    #[error("Line {0}: bad payload: {1}")]
+    BadPayload(usize, hex::FromHexError),
+}
+
+#[derive(Debug, Clone)]
+pub enum Action {
+    Drop,
+    /// Send this frame instead.
+    Replace(Vec<u8>),
+    /// Send this frame after the matching one.
+    Inject(Vec<u8>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Rule {
+    /// `None` matches frames going either way. [`Direction::In`] is towards the server.
+    pub direction: Option<Direction>,
+    pub msg_type: u8,
+    pub action: Action,
+}
+
+impl Rule {
+    fn matches(&self, direction: Direction, msg_type: u8) -> bool {
+        self.direction.is_none_or(|d| d == direction) && self.msg_type == msg_type
+    }
+}
+
+pub fn parse_rules(text: &str) -> Result<Vec<Rule>, RuleError> {
+    let mut rules = Vec::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let line_no = i + 1;
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [action, direction, msg_type, rest @ ..] = &fields[..] else {
+            return Err(RuleError::Syntax(line_no, "action, direction and type"));
+        };
+
+        let direction = match *direction {
+            "to-sign" => Some(Direction::Out),
+            "to-server" => Some(Direction::In),
+            "any" => None,
+            x => return Err(RuleError::UnknownDirection(line_no, x.to_string())),
+        };
+        let msg_type = parse_type(line_no, msg_type)?;
+
+        let new_frame = || match rest {
+            [t, payload] => {
+                let payload = match *payload {
+                    "-" => vec![],
+                    p => hex::decode(p).map_err(|e| RuleError::BadPayload(line_no, e))?,
+                };
+                Ok(msg::frame(parse_type(line_no, t)?, &payload))
+            }
+            _ => Err(RuleError::Syntax(line_no, "new type and payload")),
+        };
+
+        let action = match *action {
+            "drop" => Action::Drop,
+            "replace" => Action::Replace(new_frame()?),
+            "inject" => Action::Inject(new_frame()?),
+            x => return Err(RuleError::UnknownAction(line_no, x.to_string())),
+        };
+
+        rules.push(Rule {
+            direction,
+            msg_type,
+            action,
+        });
+    }
+
+    Ok(rules)
+}
+
+fn parse_type(line_no: usize, t: &str) -> Result<u8, RuleError> {
+    t.parse()
+        .map_err(|_| RuleError::BadType(line_no, t.to_string()))
+}
+
+/// Relay frames between a sign and the upstream server until either side hangs up.
+pub fn relay(
+    sign: TcpStream,
+    upstream: TcpStream,
+    rules: Arc<Vec<Rule>>,
+    recorder: Option<Recorder>,
+) -> std::io::Result<()> {
+    let to_server = {
+        let (from, to) = (sign.try_clone()?, upstream.try_clone()?);
+        let (rules, recorder) = (rules.clone(), recorder.clone());
+        thread::spawn(move || pipe(from, to, Direction::In, &rules, recorder))
+    };
+    pipe(upstream, sign, Direction::Out, &rules, recorder);
+
+    let _ = to_server.join();
+    Ok(())
+}
+
+fn pipe(
+    mut from: TcpStream,
+    mut to: TcpStream,
+    direction: Direction,
+    rules: &[Rule],
+    recorder: Option<Recorder>,
+) {
+    let arrow = match direction {
+        Direction::In => "sign -> server",
+        Direction::Out => "server -> sign",
+    };
+
+    loop {
+        let frame = match msg::read_frame(&mut from) {
+            Ok(frame) => frame,
+            Err(DecodeError::Io(e)) => {
+                log::info!("{arrow}: connection closed: {e}");
+                break;
+            }
+            Err(e) => {
+                // We no longer know where frames start, so stop looking at them.
+                log::warn!("{arrow}: lost framing ({e}), relaying raw bytes from now on.");
+                let read = match &e {
+                    DecodeError::BadLength(_, header) => &header[..],
+                    _ => &[],
+                };
+                if let Err(e) = to
+                    .write_all(read)
+                    .and_then(|_| std::io::copy(&mut from, &mut to))
+                {
+                    log::info!("{arrow}: connection closed: {e}");
+                }
+                break;
+            }
+        };
+
+        // The decoders trust the length fields they are given, so a bad frame can panic.
+        match catch_unwind(|| Message::decode(&frame[..])) {
+            Ok(Ok(msg)) => {
+                log::info!("{arrow}: {msg:?}");
+                if let Some(recorder) = &recorder {
+                    recorder.record(direction, &frame, &msg);
+                }
+            }
+            Ok(Err(e)) => log::warn!("{arrow}: undecodable frame ({e}): {}", hex::encode(&frame)),
+            Err(_) => log::warn!(
+                "{arrow}: decoder panicked on frame: {}",
+                hex::encode(&frame)
+            ),
+        }
+
+        let msg_type = frame[0];
+        let mut out = vec![frame];
+        for rule in rules.iter().filter(|r| r.matches(direction, msg_type)) {
+            log::info!("{arrow}: applying {rule:?}");
+            match &rule.action {
+                Action::Drop => out.clear(),
+                Action::Replace(f) => out = vec![f.clone()],
+                Action::Inject(f) => out.push(f.clone()),
+            }
+            if out.is_empty() {
+                break;
+            }
+        }
+
+        if let Err(e) = out.iter().try_for_each(|f| to.write_all(f)) {
+            log::info!("{arrow}: connection closed: {e}");
+            break;
+        }
+    }
+
+    // Take the other direction down with us.
+    let _ = from.shutdown(std::net::Shutdown::Both);
+    let _ = to.shutdown(std::net::Shutdown::Both);
+}
@@ -0,0 +1,168 @@
+//! Append-only recordings of the frames exchanged with a sign.
+//!
+//! A recording is a text file made of sessions, one per connection. Each session starts with a
+//! header line:
+//!
+//! ```text
+//! # session <RFC 3339 wall-clock time> <peer address>
+//! ```
+//!
+//! and is followed by one line per frame, with tab-separated fields: microseconds since the start
+//! of the session (monotonic), direction (`in` from the sign, `out` to the sign), the raw frame in
+//! hex, and the decoded message in its debug form. The debug form is only there for people
+//! reading the file; the raw frame is what gets decoded when a recording is read back.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+
+use crate::msg::{DecodeError, Message};
+
+#[derive(Error, Debug)]
+pub enum RecordError {
+    #[error("Failed i/o: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Malformed record on line {0}: {1}")]
+    Malformed(usize, String),
+    #[error("Couldn't decode frame on line {0}: {1}")]
+    Decode(usize, DecodeError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Sent by the sign to the server.
+    In,
+    /// Sent by the server to the sign.
+    Out,
+}
+
+impl Direction {
+    fn as_str(self) -> &'static str {
+        match self {
+            Direction::In => "in",
+            Direction::Out => "out",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub at: Duration,
+    pub direction: Direction,
+    pub frame: Vec<u8>,
+    pub msg: Message,
+}
+
+#[derive(Debug, Clone)]
+pub struct Session {
+    /// Everything following `# session` on the header line.
+    pub header: String,
+    pub records: Vec<Record>,
+}
+
+/// Writes the frames of one connection to a recording. Cloning is cheap, and clones share the
+/// underlying file, so the reader and writer halves of a connection can both hold one.
+#[derive(Clone)]
+pub struct Recorder {
+    start: Instant,
+    file: Arc<Mutex<File>>,
+}
+
+impl Recorder {
+    /// Open (or create) the recording at `path` for appending and start a new session in it.
+    pub fn create(path: impl AsRef<Path>, peer: SocketAddr) -> std::io::Result<Self> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "# session {} {peer}", chrono::Utc::now().to_rfc3339())?;
+
+        Ok(Recorder {
+            start: Instant::now(),
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    pub fn record(&self, direction: Direction, frame: &[u8], msg: &Message) {
+        let at = self.start.elapsed().as_micros();
+        let line = format!(
+            "{at}\t{}\t{}\t{msg:?}\n",
+            direction.as_str(),
+            hex::encode(frame)
+        );
+
+        // A poisoned lock only means another thread panicked mid-write; keep recording.
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            log::warn!("Failed to write to recording: {e}");
+        }
+    }
+}
+
+/// Read every session out of a recording.
+pub fn read(path: impl AsRef<Path>) -> Result<Vec<Session>, RecordError> {
+    let file = BufReader::new(File::open(path)?);
+    let mut sessions: Vec<Session> = Vec::new();
+
+    for (i, line) in file.lines().enumerate() {
+        let line = line?;
+        let line_no = i + 1;
+
+        if let Some(header) = line.strip_prefix("# session") {
+            sessions.push(Session {
+                header: header.trim().to_string(),
+                records: Vec::new(),
+            });
+            continue;
+        }
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let Some(session) = sessions.last_mut() else {
+            return Err(RecordError::Malformed(
+                line_no,
+                "record before any session header".to_string(),
+            ));
+        };
+
+        let mut fields = line.splitn(4, '\t');
+        let (Some(at), Some(direction), Some(frame)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            return Err(RecordError::Malformed(
+                line_no,
+                "expected at least 3 fields".to_string(),
+            ));
+        };
+
+        let at = at
+            .parse()
+            .map(Duration::from_micros)
+            .map_err(|e| RecordError::Malformed(line_no, format!("bad timestamp: {e}")))?;
+        let direction = match direction {
+            "in" => Direction::In,
+            "out" => Direction::Out,
+            x => {
+                return Err(RecordError::Malformed(
+                    line_no,
+                    format!("unknown direction {x:?}"),
+                ));
+            }
+        };
+        let frame = hex::decode(frame)
+            .map_err(|e| RecordError::Malformed(line_no, format!("bad frame: {e}")))?;
+        let msg = Message::decode(&frame[..]).map_err(|e| RecordError::Decode(line_no, e))?;
+
+        session.records.push(Record {
+            at,
+            direction,
+            frame,
+            msg,
+        });
+    }
+
+    Ok(sessions)
+}
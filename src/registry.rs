@@ -0,0 +1,193 @@
+//! Which signs we know about, and what we last heard from each of them.
+//!
+//! Signs are identified when they connect, by the IP or MAC address mappings in the config. The
+//! MAC address is looked up in the kernel's ARP table, so it only works for signs on the server's
+//! own network segment. Neither `AuthRequest` nor `AppRunning` carry anything that tells signs
+//! apart, so what they say is recorded in the state, and signs that aren't in the config are known
+//! by their IP address.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Mutex, MutexGuard};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::config::SignConfig;
+use crate::msg::Message;
+use crate::msg::app_running::AppRunningReason;
+use crate::stops::Stop;
+
+pub type SignId = String;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SignState {
+    pub id: SignId,
+    pub online: bool,
+    pub peer: Option<SocketAddr>,
+    pub mac: Option<String>,
+    pub connected_at: Option<DateTime<Utc>>,
+    pub last_ping: Option<DateTime<Utc>>,
+    pub last_app_running: Option<AppRunningReason>,
+    pub auth_method: Option<u8>,
+    /// As reported in the last `AckSyncClock`.
+    pub clock_drift_sec: Option<u16>,
+    /// Content the sign has acknowledged without error.
+    pub acked_content: BTreeSet<u16>,
+    /// Stop configuration the sign has acknowledged without error, by stop id.
+    pub stops: BTreeMap<u8, Stop>,
+}
+
+impl SignState {
+    fn new(id: SignId) -> Self {
+        SignState {
+            id,
+            online: false,
+            peer: None,
+            mac: None,
+            connected_at: None,
+            last_ping: None,
+            last_app_running: None,
+            auth_method: None,
+            clock_drift_sec: None,
+            acked_content: BTreeSet::new(),
+            stops: BTreeMap::new(),
+        }
+    }
+}
+
+pub struct Registry {
+    by_ip: BTreeMap<IpAddr, SignId>,
+    by_mac: BTreeMap<String, SignId>,
+    signs: Mutex<BTreeMap<SignId, SignState>>,
+}
+
+impl Registry {
+    /// Start a registry holding the configured signs, all offline.
+    pub fn new(signs: &[SignConfig]) -> Self {
+        Registry {
+            by_ip: signs
+                .iter()
+                .filter_map(|s| Some((s.ip?, s.id.clone())))
+                .collect(),
+            by_mac: signs
+                .iter()
+                .filter_map(|s| Some((s.mac.as_ref()?.to_lowercase(), s.id.clone())))
+                .collect(),
+            signs: Mutex::new(
+                signs
+                    .iter()
+                    .map(|s| (s.id.clone(), SignState::new(s.id.clone())))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Work out which sign is connecting from `peer`, and mark it online.
+    pub fn connect(&self, peer: SocketAddr) -> SignId {
+        let mac = arp_lookup(peer.ip());
+        let id = self
+            .by_ip
+            .get(&peer.ip())
+            .or_else(|| self.by_mac.get(mac.as_ref()?))
+            .cloned()
+            .unwrap_or_else(|| peer.ip().to_string());
+
+        let mut signs = self.signs();
+        let state = signs
+            .entry(id.clone())
+            .or_insert_with(|| SignState::new(id.clone()));
+        if state.online {
+            log::warn!("Sign {id} connected from {peer} while already online.");
+        }
+
+        // What the sign held is gone once it has reconnected.
+        *state = SignState {
+            online: true,
+            peer: Some(peer),
+            mac,
+            connected_at: Some(Utc::now()),
+            ..SignState::new(id.clone())
+        };
+
+        id
+    }
+
+    /// Mark a sign offline, unless it has since reconnected from somewhere else.
+    pub fn disconnect(&self, id: &str, peer: SocketAddr) {
+        if let Some(state) = self.signs().get_mut(id)
+            && state.peer == Some(peer)
+        {
+            state.online = false;
+        }
+    }
+
+    /// Update a sign's state with a message it sent.
+    pub fn observe(&self, id: &str, msg: &Message) {
+        let mut signs = self.signs();
+        let Some(state) = signs.get_mut(id) else {
+            return;
+        };
+
+        match msg {
+            Message::Ping { .. } => state.last_ping = Some(Utc::now()),
+            Message::AppRunning { reason, .. } => state.last_app_running = Some(*reason),
+            Message::AuthRequest { method } => state.auth_method = Some(*method),
+            Message::AckSyncClock { drift_sec, .. } => state.clock_drift_sec = Some(*drift_sec),
+            _ => {}
+        }
+    }
+
+    /// Update a sign's state with its acknowledgement of something we sent.
+    pub fn observe_ack(&self, id: &str, sent: &Message, ack: &Message) {
+        let mut signs = self.signs();
+        let Some(state) = signs.get_mut(id) else {
+            return;
+        };
+        if ack.ack_error() != Some(0) {
+            return;
+        }
+
+        match sent {
+            Message::ContentMsg { content_id, .. } => {
+                state.acked_content.insert(*content_id);
+            }
+            Message::ContentDelete { content_id } => {
+                state.acked_content.remove(content_id);
+            }
+            Message::StopCfg { stop_id, .. } => {
+                if let Some(stop) = Stop::from_message(sent) {
+                    state.stops.insert(*stop_id, stop);
+                }
+            }
+            Message::ClearStopCfg => state.stops.clear(),
+            _ => {}
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<SignState> {
+        self.signs().get(id).cloned()
+    }
+
+    pub fn list(&self) -> Vec<SignState> {
+        self.signs().values().cloned().collect()
+    }
+
+    fn signs(&self) -> MutexGuard<'_, BTreeMap<SignId, SignState>> {
+        self.signs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Find the MAC address for an IP address in the kernel's ARP table.
+fn arp_lookup(ip: IpAddr) -> Option<String> {
+    let table = std::fs::read_to_string("/proc/net/arp").ok()?;
+    let ip = ip.to_string();
+
+    table.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields[..] {
+            [addr, _, _, mac, ..] if addr == ip => Some(mac.to_lowercase()),
+            _ => None,
+        }
+    })
+}
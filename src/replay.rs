@@ -0,0 +1,116 @@
+//! Deterministic replay of recorded sessions against the server's response logic.
+//!
+//! Frames received from the sign are fed through [`respond_to`], and whatever it answers is
+//! checked against the frames the server actually sent at the time, including responses the
+//! server sent that the replay didn't. Frames the server sent on its own accord (content, clock
+//! marks, ...) are not checked, but are used to rebuild the state [`respond_to`] depends on.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::msg::Message;
+use crate::record::{Direction, Record};
+use crate::server::{ClockMark, respond_to};
+
+#[derive(Debug)]
+pub enum Mismatch {
+    /// The server sent `recorded`, but the replay answered with `replayed`.
+    Differs {
+        at: Duration,
+        recorded: Message,
+        replayed: Message,
+    },
+    /// The replay answered with a message that the server never sent.
+    Unexpected { replayed: Message },
+    /// The server sent a response that the replay didn't.
+    Unanswered { at: Duration, recorded: Message },
+}
+
+/// Replay one session, returning every response that didn't match the recording. `tz` is the
+/// POSIX TZ string the server would have given the sign in `SyncClock`.
+pub fn replay(records: &[Record], tz: &str) -> Vec<Mismatch> {
+    let mut clk_mark = None;
+    let mut replayed = VecDeque::new();
+    let mut mismatches = Vec::new();
+
+    for record in records {
+        match record.direction {
+            Direction::In => {
+                if let Some(resp) = respond_to(record.msg.clone(), clk_mark.as_ref()) {
+                    replayed.push_back(resp);
+                }
+            }
+            Direction::Out => match replayed
+                .iter()
+                .position(|resp| resp.get_type() == record.msg.get_type())
+            {
+                Some(i) => {
+                    // Anything the replay answered before this, the server never sent.
+                    mismatches.extend(
+                        replayed
+                            .drain(..i)
+                            .map(|replayed| Mismatch::Unexpected { replayed }),
+                    );
+                    let resp = replayed.pop_front().unwrap();
+                    if !equivalent(&resp, &record.msg) {
+                        mismatches.push(Mismatch::Differs {
+                            at: record.at,
+                            recorded: record.msg.clone(),
+                            replayed: resp,
+                        });
+                    }
+                }
+                _ if is_response(&record.msg) => mismatches.push(Mismatch::Unanswered {
+                    at: record.at,
+                    recorded: record.msg.clone(),
+                }),
+                _ => {
+                    if let Message::MarkClock { sequence } = record.msg {
+                        // The wall-clock time of the mark isn't part of the recording, see
+                        // `equivalent`.
+                        clk_mark = Some(ClockMark {
+                            epoch_sec: 0,
+                            seq_num: sequence,
+                            tz: tz.to_string(),
+                        });
+                    }
+                }
+            },
+        }
+    }
+
+    mismatches.extend(
+        replayed
+            .into_iter()
+            .map(|replayed| Mismatch::Unexpected { replayed }),
+    );
+    mismatches
+}
+
+/// Whether a message is one [`respond_to`] answers with, rather than one the server sends
+/// unprompted.
+fn is_response(msg: &Message) -> bool {
+    matches!(msg, Message::Pong { .. } | Message::SyncClock { .. })
+}
+
+/// Compare a replayed response with a recorded one, ignoring the fields that depend on the wall
+/// clock at the time of recording.
+fn equivalent(replayed: &Message, recorded: &Message) -> bool {
+    match (replayed, recorded) {
+        (
+            Message::SyncClock {
+                seq_num: a,
+                tz: ta,
+                zone_offset: za,
+                ..
+            },
+            Message::SyncClock {
+                seq_num: b,
+                tz: tb,
+                zone_offset: zb,
+                ..
+            },
+        ) => a == b && ta == tb && za == zb,
+        _ => replayed == recorded,
+    }
+}
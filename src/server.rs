@@ -0,0 +1,143 @@
+use std::sync::Arc;
+
+use thiserror::Error;
+
+use crate::clocks::Clocks;
+use crate::config::{Config, ConfigError};
+use crate::content;
+use crate::firmware::Firmware;
+use crate::hub::{Hub, Outcome};
+use crate::impressions::{Impressions, ImpressionsError};
+use crate::msg::Message;
+use crate::phonemes::Lexicon;
+use crate::registry::Registry;
+use crate::settings;
+use crate::sounds::{SoundError, Sounds};
+use crate::stops;
+use crate::store::{Store, StoreError};
+use crate::tz::Rules;
+
+#[derive(Error, Debug)]
+pub enum ServerError {
+    #[error("Couldn't open desired state: {0}")]
+    Store(#[from] StoreError),
+    #[error("Couldn't open impressions: {0}")]
+    Impressions(#[from] ImpressionsError),
+    #[error("Couldn't open sound library: {0}")]
+    Sounds(#[from] SoundError),
+    #[error("Couldn't load lexicon: {0}")]
+    Lexicon(#[from] ConfigError),
+}
+
+/// Everything shared between sign connections and the HTTP server.
+pub struct Server {
+    pub config: Config,
+    pub hub: Arc<Hub>,
+    pub registry: Registry,
+    pub store: Store,
+    pub impressions: Impressions,
+    pub sounds: Sounds,
+    pub lexicon: Option<Lexicon>,
+    pub clocks: Clocks,
+    pub firmware: Firmware,
+    /// Time zones' rules, as sent to signs.
+    pub tz_rules: Rules,
+}
+
+impl Server {
+    pub fn new(config: Config) -> Result<Self, ServerError> {
+        let server = Server {
+            hub: Arc::new(Hub::default()),
+            registry: Registry::new(&config.signs),
+            store: Store::open(config.state_path.clone())?,
+            impressions: Impressions::open(config.impressions_path.clone())?,
+            sounds: Sounds::open(config.sound_dir.clone(), config.sound_url.clone())?,
+            lexicon: config.lexicon.as_ref().map(Lexicon::load).transpose()?,
+            clocks: Clocks::new(&config.clock),
+            firmware: Firmware::new(&config.firmware),
+            tz_rules: Rules::default(),
+            config,
+        };
+        settings::assign_profiles(&server);
+        stops::seed(&server);
+
+        Ok(server)
+    }
+
+    /// Push a sign's desired state to it one item at a time, checking that each is acknowledged.
+    pub fn restore(&self, sign: &str) {
+        let msgs = self
+            .store
+            .get(sign)
+            .messages(|stop| stops::message(self, stop));
+        if msgs.is_empty() {
+            return;
+        }
+        log::info!("Restoring {} item(s) to {sign}.", msgs.len());
+
+        let mut failed = 0;
+        for msg in msgs {
+            for delivery in self.hub.send_to(&[sign.to_string()], &msg) {
+                if let (Message::ContentDelete { content_id }, Outcome::Acked { error }) =
+                    (&msg, &delivery.outcome)
+                {
+                    content::confirm_withdrawn(self, sign, *content_id, *error);
+                    continue;
+                }
+
+                match delivery.outcome {
+                    Outcome::Acked { error: 0 } => {}
+                    Outcome::Offline | Outcome::Disconnected => {
+                        log::warn!("{sign} went away while restoring its state.");
+                        return;
+                    }
+                    outcome => {
+                        log::warn!("Restoring {msg:?} to {sign}: {outcome:?}");
+                        failed += 1;
+                    }
+                }
+            }
+        }
+
+        if failed == 0 {
+            log::info!("Restored {sign}.");
+        } else {
+            log::warn!("Restored {sign} with {failed} failure(s).");
+        }
+    }
+}
+
+/// The server's side of an outstanding `MarkClock`: when it was sent, which sequence number the
+/// sign should acknowledge, and the POSIX TZ string to sync the sign to.
+#[derive(Clone)]
+pub struct ClockMark {
+    pub epoch_sec: u32, // 2038 will never happen.
+    pub seq_num: u8,
+    pub tz: String,
+}
+
+/// Work out the server's reply, if any, to a message received from a sign.
+pub fn respond_to(msg: Message, clk_mark: Option<&ClockMark>) -> Option<Message> {
+    match msg {
+        Message::Ping { seq_num } => Some(Message::Pong { seq_num }),
+        Message::AckMarkClock { seq_num } => match clk_mark {
+            None => {
+                log::warn!("Received unrequested AckMarkClock.");
+                None
+            }
+            Some(&ClockMark { seq_num: x, .. }) if x != seq_num => {
+                log::warn!("Wrong MarkClock Ack'd: Saw {seq_num}, expected {x}");
+                None
+            }
+            Some(mark) => {
+                Some(Message::SyncClock {
+                    epoch_time_sec: mark.epoch_sec,
+                    seq_num,
+                    tz: mark.tz.clone(),
+                    zone_offset: 0, // unused so far as I can tell
+                })
+            }
+        },
+        _ => None,
+    }
+}
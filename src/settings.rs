@@ -0,0 +1,181 @@
+//! Sign settings: config parameters, and the profiles that set several of them at once.
+//!
+//! Parameters that are set are kept in each sign's desired state, so they're set again when the
+//! sign reconnects. Signs with a profile in the server config are given it when the server starts,
+//! in place of any other profile and its parameters.
+
+use serde::Serialize;
+
+use crate::hub::{Delivery, Outcome};
+use crate::msg::Message;
+use crate::registry::SignId;
+use crate::server::Server;
+
+/// One parameter as read from a sign.
+#[derive(Debug, Serialize)]
+pub struct Reading {
+    pub param: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// `None` if the sign couldn't be read.
+    pub value: Option<u8>,
+    /// The value the sign's profile gives the parameter, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected: Option<u8>,
+    #[serde(flatten)]
+    pub delivery: Delivery,
+}
+
+impl Reading {
+    fn drifted(&self) -> bool {
+        self.expected.is_some() && self.value != self.expected
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Audit {
+    pub sign: SignId,
+    pub online: bool,
+    pub profile: Option<String>,
+    /// Whether any parameter differs from the profile.
+    pub drifted: bool,
+    pub readings: Vec<Reading>,
+}
+
+/// Read a parameter from a sign.
+pub fn read(server: &Server, sign: &str, param: u8) -> Vec<Reading> {
+    readings(server, &[sign.to_string()], param)
+}
+
+/// Set a parameter on a sign.
+pub fn set(server: &Server, sign: &str, param: u8, value: u8) -> Vec<Delivery> {
+    server
+        .store
+        .update(sign, |state| state.cfg.insert(param, value));
+    server
+        .hub
+        .send_to(&[sign.to_string()], &Message::SetCfgParam { param, value })
+}
+
+/// Put every parameter on a sign back to its default, forgetting its profile.
+pub fn reset(server: &Server, sign: &str) -> Vec<Delivery> {
+    server.store.update(sign, |state| {
+        state.cfg.clear();
+        state.profile = None;
+    });
+    server
+        .hub
+        .send_to(&[sign.to_string()], &Message::ResetCfgParams)
+}
+
+/// Set every parameter in a profile on a sign. `None` if there's no such profile.
+pub fn apply_profile(server: &Server, sign: &str, profile: &str) -> Option<Vec<Delivery>> {
+    let values = server.config.profile(profile)?;
+    server.store.update(sign, |state| {
+        state.cfg.extend(&values);
+        state.profile = Some(profile.to_string());
+    });
+
+    let sign = [sign.to_string()];
+    Some(
+        values
+            .into_iter()
+            .flat_map(|(param, value)| {
+                server
+                    .hub
+                    .send_to(&sign, &Message::SetCfgParam { param, value })
+            })
+            .collect(),
+    )
+}
+
+/// Give every configured sign the profile the server config assigns it. A sign with some other
+/// profile loses that profile's parameters; one that already has it gets any values changed since.
+/// Nothing is sent; signs pick it up when they connect.
+pub fn assign_profiles(server: &Server) {
+    for sign in &server.config.signs {
+        let Some(profile) = server.config.assigned_profile(&sign.id) else {
+            continue;
+        };
+        let Some(values) = server.config.profile(profile) else {
+            continue;
+        };
+
+        server.store.update(&sign.id, |state| {
+            if state.profile.as_deref() != Some(profile) {
+                log::info!("Giving {} the {profile} profile.", sign.id);
+                state.cfg.clear();
+                state.profile = Some(profile.to_string());
+            }
+            state.cfg.extend(&values);
+        });
+    }
+}
+
+/// Read every known parameter from every sign, and compare them with the signs' profiles. The
+/// known parameters are the named ones plus any that a sign has been given.
+pub fn audit(server: &Server) -> Vec<Audit> {
+    let signs = server.registry.list();
+    let online: Vec<SignId> = signs
+        .iter()
+        .filter(|s| s.online)
+        .map(|s| s.id.clone())
+        .collect();
+
+    let mut params: Vec<u8> = server.config.params.values().copied().collect();
+    for sign in &online {
+        params.extend(server.store.get(sign).cfg.keys());
+    }
+    params.sort();
+    params.dedup();
+
+    // Each parameter is read from every sign at once, so a slow sign only holds up one round.
+    let mut readings: Vec<Reading> = params
+        .iter()
+        .flat_map(|param| readings(server, &online, *param))
+        .collect();
+
+    signs
+        .into_iter()
+        .map(|sign| {
+            let profile = server.store.get(&sign.id).profile;
+            let values = profile.as_deref().and_then(|p| server.config.profile(p));
+
+            let (mut mine, rest) = readings
+                .drain(..)
+                .partition::<Vec<_>, _>(|r| r.delivery.sign == sign.id);
+            readings = rest;
+            for reading in &mut mine {
+                reading.expected = values.as_ref().and_then(|v| v.get(&reading.param).copied());
+            }
+
+            Audit {
+                drifted: mine.iter().any(Reading::drifted),
+                sign: sign.id,
+                online: sign.online,
+                profile,
+                readings: mine,
+            }
+        })
+        .collect()
+}
+
+fn readings(server: &Server, signs: &[SignId], param: u8) -> Vec<Reading> {
+    server
+        .hub
+        .send_to(signs, &Message::GetCfgParam { param })
+        .into_iter()
+        .map(|delivery| Reading {
+            param,
+            name: server.config.param_name(param).map(str::to_string),
+            value: match (&delivery.outcome, &delivery.ack) {
+                (Outcome::Acked { error: 0 }, Some(Message::AckGetCfgParam { value, .. })) => {
+                    Some(*value)
+                }
+                _ => None,
+            },
+            expected: None,
+            delivery,
+        })
+        .collect()
+}
@@ -0,0 +1,248 @@
+//! A library of sounds, served to signs over the HTTP API.
+//!
+//! Sounds are files in `sound_dir`, by file name. Content and stops name the sound they play,
+//! and the server fills in the URL signs fetch it from and its MD5: `SoundURL` and
+//! `SoundChecksum` payloads for content, `snd_url` and `snd_md5` for stops. The URL carries the
+//! checksum, so each version of a sound has its own, and only the current version is served. When
+//! a sound changes, whether it's uploaded or changed on disk, everything that plays it is sent to
+//! signs again with the new version.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard};
+
+use md5::{Digest, Md5};
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::content::{self, Content};
+use crate::hub::Delivery;
+use crate::msg::content::PayloadType;
+use crate::server::Server;
+use crate::stops::{self, Stop};
+
+#[derive(Error, Debug)]
+pub enum SoundError {
+    #[error("Failed i/o: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("No sound library is configured.")]
+    NoLibrary,
+    #[error("No sound {0}.")]
+    Unknown(String),
+    #[error("Invalid sound name {0:?}.")]
+    InvalidName(String),
+    #[error("Sound {0} has changed since that version.")]
+    Changed(String),
+}
+
+/// The longest a sound's name can be.
+pub const MAX_NAME_LEN: usize = 64;
+
+/// How much longer a sound's URL is than `sound_url`, at most.
+pub const URL_OVERHEAD: usize = "/sounds/".len() + MAX_NAME_LEN + "?md5=".len() + 32;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Sound {
+    pub name: String,
+    pub md5: String,
+    pub size: usize,
+    /// Where signs fetch this version from.
+    pub url: String,
+}
+
+pub struct Sounds {
+    dir: Option<PathBuf>,
+    base_url: String,
+    sounds: Mutex<BTreeMap<String, Sound>>,
+}
+
+impl Sounds {
+    /// Open the library in `dir`, whose sounds signs fetch through `base_url`, the address they
+    /// reach the HTTP API at. Without a directory there are no sounds.
+    pub fn open(dir: Option<PathBuf>, base_url: Option<String>) -> Result<Self, SoundError> {
+        let sounds = Sounds {
+            dir,
+            base_url: base_url
+                .unwrap_or_default()
+                .trim_end_matches('/')
+                .to_string(),
+            sounds: Mutex::new(BTreeMap::new()),
+        };
+        sounds.scan()?;
+        Ok(sounds)
+    }
+
+    pub fn get(&self, name: &str) -> Option<Sound> {
+        self.xs().get(name).cloned()
+    }
+
+    pub fn list(&self) -> Vec<Sound> {
+        self.xs().values().cloned().collect()
+    }
+
+    /// A sound's current contents, as long as they're the version with the given MD5, if one is
+    /// given. Signs check what they fetch against the MD5 they were sent, so there's no point
+    /// sending them another version.
+    pub fn read(&self, name: &str, md5: Option<&str>) -> Result<Vec<u8>, SoundError> {
+        let dir = self.dir.as_ref().ok_or(SoundError::NoLibrary)?;
+        if self.get(name).is_none() {
+            return Err(SoundError::Unknown(name.to_string()));
+        }
+        let bytes = std::fs::read(dir.join(name))?;
+        if let Some(md5) = md5
+            && !md5.eq_ignore_ascii_case(&hex::encode(Md5::digest(&bytes)))
+        {
+            return Err(SoundError::Changed(name.to_string()));
+        }
+        Ok(bytes)
+    }
+
+    /// Add a sound to the library, or replace it. Whether it's changed.
+    pub fn put(&self, name: &str, bytes: &[u8]) -> Result<bool, SoundError> {
+        let dir = self.dir.as_ref().ok_or(SoundError::NoLibrary)?;
+        if !valid_name(name) {
+            return Err(SoundError::InvalidName(name.to_string()));
+        }
+
+        // Write then rename, so signs never fetch half a sound.
+        let tmp = dir.join(format!(".{name}.tmp"));
+        std::fs::write(&tmp, bytes)?;
+        std::fs::rename(&tmp, dir.join(name))?;
+
+        let sound = self.sound(name, bytes);
+        Ok(self.xs().insert(name.to_string(), sound.clone()) != Some(sound))
+    }
+
+    /// Read the library again. The names of the sounds that have been added, changed or removed.
+    pub fn scan(&self) -> Result<BTreeSet<String>, SoundError> {
+        let Some(dir) = &self.dir else {
+            return Ok(BTreeSet::new());
+        };
+
+        let mut found = BTreeMap::new();
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            if !valid_name(&name) || !entry.file_type()?.is_file() {
+                continue;
+            }
+            let sound = self.sound(&name, &std::fs::read(entry.path())?);
+            found.insert(name, sound);
+        }
+
+        let mut sounds = self.xs();
+        let changed = found
+            .keys()
+            .chain(sounds.keys())
+            .filter(|name| found.get(*name) != sounds.get(*name))
+            .cloned()
+            .collect();
+        *sounds = found;
+        Ok(changed)
+    }
+
+    /// Fill in the `SoundURL` and `SoundChecksum` payloads for the sound an item plays, replacing
+    /// any it had.
+    pub fn fill_content(&self, content: &mut Content) -> Result<(), SoundError> {
+        let Some(name) = &content.sound else {
+            return Ok(());
+        };
+        let sound = self
+            .get(name)
+            .ok_or_else(|| SoundError::Unknown(name.clone()))?;
+
+        content.payloads.retain(|(kind, _)| {
+            !matches!(kind, PayloadType::SoundURL | PayloadType::SoundChecksum)
+        });
+        content
+            .payloads
+            .push((PayloadType::SoundURL, sound.url.into_bytes()));
+        content
+            .payloads
+            .push((PayloadType::SoundChecksum, sound.md5.into_bytes()));
+        Ok(())
+    }
+
+    /// Fill in `snd_url` and `snd_md5` for the sound a stop plays.
+    pub fn fill_stop(&self, stop: &mut Stop) -> Result<(), SoundError> {
+        let Some(name) = &stop.sound else {
+            return Ok(());
+        };
+        let sound = self
+            .get(name)
+            .ok_or_else(|| SoundError::Unknown(name.clone()))?;
+
+        stop.snd_url = sound.url;
+        stop.snd_md5 = sound.md5;
+        Ok(())
+    }
+
+    fn sound(&self, name: &str, bytes: &[u8]) -> Sound {
+        let md5 = hex::encode(Md5::digest(bytes));
+        Sound {
+            name: name.to_string(),
+            url: format!("{}/sounds/{name}?md5={md5}", self.base_url),
+            md5,
+            size: bytes.len(),
+        }
+    }
+
+    fn xs(&self) -> MutexGuard<'_, BTreeMap<String, Sound>> {
+        self.sounds.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Names that are safe to use as file names in the library, and in URLs, and short enough that
+/// their URLs fit in a `StopCfg`.
+fn valid_name(name: &str) -> bool {
+    (1..=MAX_NAME_LEN).contains(&name.len())
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+}
+
+/// Look for sounds changed on disk, and send signs anything that plays one.
+pub fn rescan(server: &Server) {
+    match server.sounds.scan() {
+        Ok(changed) if !changed.is_empty() => {
+            log::info!("Sounds changed: {changed:?}");
+            refresh(server, &changed);
+        }
+        Ok(_) => {}
+        Err(e) => log::warn!("Couldn't scan sounds: {e}"),
+    }
+}
+
+/// Send signs every item and stop that plays one of `changed`, with its sound filled in again.
+pub fn refresh(server: &Server, changed: &BTreeSet<String>) -> Vec<Delivery> {
+    let plays = |sound: &Option<String>| sound.as_ref().is_some_and(|s| changed.contains(s));
+    let mut deliveries = Vec::new();
+
+    for sign in server.store.signs() {
+        let desired = server.store.get(&sign);
+
+        for content in desired.content.values().filter(|c| plays(&c.sound)) {
+            let mut content = content.clone();
+            if let Err(e) = server.sounds.fill_content(&mut content) {
+                log::warn!("Content {} on {sign}: {e}", content.content_id);
+                continue;
+            }
+            deliveries.extend(content::replace(server, &sign, content).unwrap_or_default());
+        }
+
+        let mut stops = desired.stops.unwrap_or_default();
+        if stops.iter().any(|s| plays(&s.sound)) {
+            for stop in &mut stops {
+                if let Err(e) = server.sounds.fill_stop(stop) {
+                    log::warn!("Stop {} on {sign}: {e}", stop.stop_id);
+                }
+            }
+            deliveries.extend(stops::set_all(server, &sign, stops));
+        }
+    }
+
+    deliveries
+}
@@ -0,0 +1,66 @@
+//! Which stop on which sign each of a transit feed's stops is, read from a TOML file.
+//!
+//! ```toml
+//! [[stops]]
+//! feed_stop = "70011"
+//! sign = "union-station-1"
+//! stop_id = 1
+//! # Optional; otherwise the feed's name for the stop is used.
+//! title = "Union Station"
+//!
+//! [[stops]]
+//! feed_stop = "70011"
+//! sign = "union-station-2"
+//! stop_id = 1
+//! ```
+//!
+//! A feed stop can be on any number of signs, and a sign can show any number of feed stops.
+
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::config::ConfigError;
+use crate::registry::SignId;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StopMap {
+    pub stops: Vec<MappedStop>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MappedStop {
+    /// The feed's id for the stop.
+    pub feed_stop: String,
+    pub sign: SignId,
+    /// The sign's id for the stop.
+    pub stop_id: u8,
+    pub title: Option<String>,
+}
+
+impl StopMap {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let map: StopMap = toml::from_str(&std::fs::read_to_string(path)?)?;
+
+        for (i, a) in map.stops.iter().enumerate() {
+            if let Some(b) = map.stops[..i]
+                .iter()
+                .find(|b| b.sign == a.sign && b.stop_id == a.stop_id)
+            {
+                return Err(ConfigError::Invalid(format!(
+                    "stop {} on {} is mapped to both {} and {}",
+                    a.stop_id, a.sign, b.feed_stop, a.feed_stop
+                )));
+            }
+        }
+
+        Ok(map)
+    }
+
+    /// Where a feed stop is shown.
+    pub fn places(&self, feed_stop: &str) -> impl Iterator<Item = &MappedStop> {
+        self.stops.iter().filter(move |s| s.feed_stop == feed_stop)
+    }
+}
@@ -0,0 +1,224 @@
+//! Stop configuration, as set on signs with `StopCfg`.
+//!
+//! A sign's stops are part of its desired state, and are always sent as a whole: `ClearStopCfg`,
+//! then a `StopCfg` for each stop. That happens whenever the sign connects, and whenever the stops
+//! are changed. Stops given for a sign in the server config are its stops until they're changed
+//! through the API, and follow changes to the config until then.
+
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::hub::{Delivery, Outcome};
+use crate::msg::Message;
+use crate::phonemes;
+use crate::server::Server;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Stop {
+    pub stop_id: u8,
+    pub title: String,
+    #[serde(default)]
+    pub phoneme: String,
+    #[serde(default)]
+    pub route_tag: String,
+    #[serde(default)]
+    pub snd_md5: String,
+    #[serde(default)]
+    pub snd_url: String,
+    #[serde(default)]
+    pub zero_countdown_msg: String,
+    /// A sound from the library, which `snd_url` and `snd_md5` are filled in from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sound: Option<String>,
+}
+
+impl Stop {
+    /// Check that every field fits in a `StopCfg`.
+    pub fn check(&self) -> Result<(), String> {
+        let fields = [
+            ("title", &self.title),
+            ("phoneme", &self.phoneme),
+            ("route_tag", &self.route_tag),
+            ("snd_md5", &self.snd_md5),
+            ("snd_url", &self.snd_url),
+            ("zero_countdown_msg", &self.zero_countdown_msg),
+        ];
+        match fields.iter().find(|(_, f)| f.len() > u8::MAX.into()) {
+            Some((name, _)) => Err(format!(
+                "stop {}: {name} is longer than {} bytes",
+                self.stop_id,
+                u8::MAX
+            )),
+            None => Ok(()),
+        }
+    }
+
+    pub fn from_message(msg: &Message) -> Option<Self> {
+        match msg {
+            Message::StopCfg {
+                stop_id,
+                title,
+                phoneme,
+                route_tag,
+                snd_md5,
+                snd_url,
+                zero_countdown_msg,
+            } => Some(Stop {
+                stop_id: *stop_id,
+                title: title.clone(),
+                phoneme: phoneme.clone(),
+                route_tag: route_tag.clone(),
+                snd_md5: snd_md5.clone(),
+                snd_url: snd_url.clone(),
+                zero_countdown_msg: zero_countdown_msg.clone(),
+                sound: None,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn to_message(&self) -> Message {
+        Message::StopCfg {
+            stop_id: self.stop_id,
+            title: self.title.clone(),
+            phoneme: self.phoneme.clone(),
+            route_tag: self.route_tag.clone(),
+            snd_md5: self.snd_md5.clone(),
+            snd_url: self.snd_url.clone(),
+            zero_countdown_msg: self.zero_countdown_msg.clone(),
+        }
+    }
+}
+
+/// Check that no two stops have the same stop id.
+pub fn check_ids(stops: &[Stop]) -> Result<(), String> {
+    let mut ids = BTreeSet::new();
+    match stops.iter().find(|s| !ids.insert(s.stop_id)) {
+        Some(stop) => Err(format!("stop {} is given more than once", stop.stop_id)),
+        None => Ok(()),
+    }
+}
+
+/// Fill in a stop's sound's URL and checksum, and check that it all fits in a `StopCfg`. Also warn
+/// if it has no phonemes and none can be made for it.
+pub fn fill(server: &Server, stop: &mut Stop) -> Result<(), String> {
+    if let Err(e) = phonemes::for_stop(server, stop) {
+        log::warn!("{e}");
+    }
+    server
+        .sounds
+        .fill_stop(stop)
+        .map_err(|e| format!("stop {}: {e}", stop.stop_id))?;
+    stop.check()
+}
+
+/// The `StopCfg` to send for a stop, with phonemes for its title if it has none of its own.
+pub fn message(server: &Server, stop: &Stop) -> Message {
+    let mut stop = stop.clone();
+    if let Ok(Some(phonemes)) = phonemes::for_stop(server, &stop) {
+        stop.phoneme = phonemes;
+    }
+    stop.to_message()
+}
+
+/// Replace all of a sign's stops.
+pub fn set_all(server: &Server, sign: &str, stops: Vec<Stop>) -> Vec<Delivery> {
+    server.store.update(sign, |state| state.stops = Some(stops));
+    push(server, sign)
+}
+
+/// Add a stop to a sign, or change the one with the same stop id.
+pub fn set(server: &Server, sign: &str, stop: Stop) -> Vec<Delivery> {
+    server.store.update(sign, |state| {
+        let stops = state.stops.get_or_insert_default();
+        stops.retain(|s| s.stop_id != stop.stop_id);
+        stops.push(stop);
+        stops.sort_by_key(|s| s.stop_id);
+    });
+    push(server, sign)
+}
+
+/// Take a stop off a sign. `None` if the sign has no such stop.
+pub fn remove(server: &Server, sign: &str, stop_id: u8) -> Option<Vec<Delivery>> {
+    server.store.update(sign, |state| {
+        let stops = state.stops.as_mut()?;
+        let i = stops.iter().position(|s| s.stop_id == stop_id)?;
+        stops.remove(i);
+        Some(())
+    })?;
+    Some(push(server, sign))
+}
+
+/// Send a sign all of its stops again, stopping at the first one it doesn't take. There's no
+/// taking a single stop off a sign, so it's cleared first.
+pub fn push(server: &Server, sign: &str) -> Vec<Delivery> {
+    let stops = server.store.get(sign).stops.unwrap_or_default();
+    let sign = [sign.to_string()];
+
+    let mut deliveries = Vec::new();
+    let msgs = stops.iter().map(|stop| message(server, stop));
+    for msg in std::iter::once(Message::ClearStopCfg).chain(msgs) {
+        let sent = server.hub.send_to(&sign, &msg);
+        let ok = sent
+            .iter()
+            .all(|d| matches!(d.outcome, Outcome::Acked { error: 0 }));
+        deliveries.extend(sent);
+        if !ok {
+            break;
+        }
+    }
+    deliveries
+}
+
+/// Give configured signs the stops in the config, unless their stops have been changed through
+/// the API since they were last given them. Nothing is sent; signs pick them up when they connect.
+pub fn seed(server: &Server) {
+    for sign in &server.config.signs {
+        let mut stops = sign.stops.clone();
+        stops.retain_mut(|stop| match fill(server, stop) {
+            Ok(()) => true,
+            Err(e) => {
+                log::warn!("{}: {e}", sign.id);
+                // Without its sound is fine, but not if it won't fit in a StopCfg.
+                stop.check().is_ok()
+            }
+        });
+        let seeded = (!sign.stops.is_empty()).then(|| sign.stops.clone());
+
+        server.store.update(&sign.id, |state| {
+            if state.seeded_stops == seeded {
+                return;
+            }
+            let current = state
+                .stops
+                .as_ref()
+                .map(|s| s.iter().map(unfilled).collect());
+            if current == state.seeded_stops {
+                state.stops = match seeded {
+                    Some(_) => Some(stops),
+                    // The config's stops are gone, so take them off the sign.
+                    None if state.stops.is_some() => Some(Vec::new()),
+                    None => None,
+                };
+            } else {
+                log::info!(
+                    "{}'s stops were changed through the API; not reseeding them.",
+                    sign.id
+                );
+            }
+            state.seeded_stops = seeded;
+        });
+    }
+}
+
+/// A stop as given, before its sound's URL and checksum are filled in.
+fn unfilled(stop: &Stop) -> Stop {
+    let mut stop = stop.clone();
+    if stop.sound.is_some() {
+        stop.snd_url.clear();
+        stop.snd_md5.clear();
+    }
+    stop
+}
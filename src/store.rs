@@ -0,0 +1,169 @@
+//! What each sign should be showing, kept on disk so it survives restarts and can be pushed to
+//! a sign again whenever it reconnects.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::content::Content;
+use crate::msg::Message;
+use crate::msg::content_schedule::Schedule;
+use crate::registry::SignId;
+use crate::stops::Stop;
+
+#[derive(Error, Debug)]
+pub enum StoreError {
+    #[error("Failed i/o: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid state file: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DesiredState {
+    /// Config parameter values.
+    pub cfg: BTreeMap<u8, u8>,
+    /// The profile last applied to the sign, which audits check its parameters against.
+    pub profile: Option<String>,
+    /// The sign's full stop configuration. `None` leaves whatever the sign has alone.
+    pub stops: Option<Vec<Stop>>,
+    /// The stops the server config gave the sign, as given. While `stops` are still these, they
+    /// follow changes to the config.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seeded_stops: Option<Vec<Stop>>,
+    /// Content, by content id.
+    pub content: BTreeMap<u16, Content>,
+    /// Content ids of items that are known by name, so they can be replaced without knowing the
+    /// id.
+    pub names: BTreeMap<String, u16>,
+    /// Content that has been withdrawn, but that the sign hasn't confirmed deleting yet.
+    pub withdrawn: BTreeSet<u16>,
+    /// Schedules for content, by content id. A `None` schedule is indefinite.
+    pub schedules: BTreeMap<u16, Option<Vec<Schedule>>>,
+}
+
+impl DesiredState {
+    /// The messages that bring a sign into this state, in the order they should be sent.
+    pub fn messages(&self, stop_message: impl Fn(&Stop) -> Message) -> Vec<Message> {
+        let mut out: Vec<Message> = self
+            .cfg
+            .iter()
+            .map(|(param, value)| Message::SetCfgParam {
+                param: *param,
+                value: *value,
+            })
+            .collect();
+
+        out.extend(
+            self.withdrawn
+                .iter()
+                .map(|id| Message::ContentDelete { content_id: *id }),
+        );
+
+        if let Some(stops) = &self.stops {
+            out.push(Message::ClearStopCfg);
+            out.extend(stops.iter().map(stop_message));
+        }
+
+        out.extend(self.content.values().map(Content::to_message));
+        out.extend(
+            self.schedules
+                .iter()
+                .filter(|(id, _)| self.content.contains_key(id))
+                .map(|(id, schedule)| schedule_message(*id, schedule.clone())),
+        );
+
+        out
+    }
+
+    /// The lowest content id that isn't in use, or on its way out.
+    pub fn free_content_id(&self) -> Option<u16> {
+        (1..=u16::MAX).find(|id| !self.content.contains_key(id) && !self.withdrawn.contains(id))
+    }
+}
+
+/// Build a `ContentSchedule`, working out the `min_time` the encoding is relative to.
+pub fn schedule_message(content_id: u16, schedule: Option<Vec<Schedule>>) -> Message {
+    let min_time = schedule
+        .iter()
+        .flatten()
+        .map(|Schedule { start, .. }| *start)
+        .min()
+        .unwrap_or(u64::MAX);
+
+    Message::ContentSchedule {
+        content_id,
+        min_time,
+        start_stop_times: schedule,
+    }
+}
+
+pub struct Store {
+    path: Option<PathBuf>,
+    signs: Mutex<BTreeMap<SignId, DesiredState>>,
+}
+
+impl Store {
+    /// Load the store from `path`, starting empty if it doesn't exist yet. Without a path,
+    /// nothing is persisted.
+    pub fn open(path: Option<PathBuf>) -> Result<Self, StoreError> {
+        let signs = match &path {
+            Some(path) if path.exists() => serde_json::from_slice(&std::fs::read(path)?)?,
+            _ => BTreeMap::new(),
+        };
+
+        Ok(Store {
+            path,
+            signs: Mutex::new(signs),
+        })
+    }
+
+    /// Every sign with a desired state.
+    pub fn signs(&self) -> Vec<SignId> {
+        self.xs().keys().cloned().collect()
+    }
+
+    pub fn get(&self, sign: &str) -> DesiredState {
+        self.xs().get(sign).cloned().unwrap_or_default()
+    }
+
+    /// Change a sign's desired state, and save the store if that changed anything.
+    pub fn update<R>(&self, sign: &str, f: impl FnOnce(&mut DesiredState) -> R) -> R {
+        let mut signs = self.xs();
+        let old = signs.get(sign);
+        let mut state = old.cloned().unwrap_or_default();
+        let r = f(&mut state);
+
+        let changed = match old {
+            Some(old) => *old != state,
+            None => state != DesiredState::default(),
+        };
+        if changed {
+            signs.insert(sign.to_string(), state);
+            if let Err(e) = self.save(&signs) {
+                log::error!("Failed to save desired state: {e}");
+            }
+        }
+        r
+    }
+
+    fn save(&self, signs: &BTreeMap<SignId, DesiredState>) -> Result<(), StoreError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        // Write then rename, so a crash never leaves a half-written file behind.
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(signs)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    fn xs(&self) -> MutexGuard<'_, BTreeMap<SignId, DesiredState>> {
+        self.signs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
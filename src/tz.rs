@@ -0,0 +1,294 @@
+//! POSIX TZ strings, which is how signs are told their time zone in `SyncClock`.
+//!
+//! Signs can't look IANA zones up, so they're given the zone's current rules, e.g.
+//! `PST8PDT,M3.2.0,M11.1.0`: standard time's abbreviation and how far it is behind UTC, daylight
+//! time's abbreviation (and how far it is behind, unless it's an hour less), and when daylight
+//! time starts and ends. Those are given as the nth weekday of a month, with 5 meaning the last,
+//! and the local time it happens at unless that's 02:00. Abbreviations that aren't all letters,
+//! such as `+09`, are put in angle brackets.
+//!
+//! The rules are worked out from the zone's transitions this year, so they're right for as long as
+//! the zone's rules stay the same and follow the usual pattern of one change each way a year.
+//! Working them out means looking at every hour of a year or two, so a [`Rules`] keeps them for
+//! each zone and year.
+//!
+//! Times given to the server, by operators or by feeds, may or may not have an offset; those
+//! without one are read as local time in a zone with [`instant`].
+
+use chrono::{
+    DateTime, Datelike, Duration, FixedOffset, Local, NaiveDate, NaiveDateTime, Offset, TimeZone,
+    Utc,
+};
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+
+use chrono_tz::{OffsetComponents, OffsetName, Tz};
+
+/// The time of day a transition is assumed to happen at when none is given.
+const DEFAULT_TRANSITION_SECS: i64 = 2 * 3600;
+
+/// One of a zone's offsets, as in effect at some instant.
+#[derive(PartialEq, Eq)]
+struct Period {
+    /// Seconds east of UTC.
+    utc_offset: i64,
+    dst: bool,
+    abbreviation: Option<String>,
+}
+
+impl Period {
+    fn at(tz: Tz, at: DateTime<Utc>) -> Self {
+        let offset = tz.offset_from_utc_datetime(&at.naive_utc());
+        Period {
+            utc_offset: (offset.base_utc_offset() + offset.dst_offset()).num_seconds(),
+            dst: !offset.dst_offset().is_zero(),
+            abbreviation: offset.abbreviation().map(str::to_string),
+        }
+    }
+
+    fn name(&self) -> String {
+        match &self.abbreviation {
+            Some(abbr) if abbr.len() >= 3 && abbr.chars().all(|c| c.is_ascii_alphabetic()) => {
+                abbr.clone()
+            }
+            Some(abbr) => format!("<{abbr}>"),
+            None => numeric_name(self.utc_offset),
+        }
+    }
+}
+
+/// POSIX TZ strings already worked out, by zone and year.
+#[derive(Default)]
+pub struct Rules {
+    rules: Mutex<HashMap<(Tz, i32), String>>,
+}
+
+impl Rules {
+    /// As [`posix_tz`], worked out once a year for each zone.
+    pub fn posix_tz(&self, tz: Tz, now: DateTime<Utc>) -> String {
+        self.xs()
+            .entry((tz, now.year()))
+            .or_insert_with(|| posix_tz(tz, now))
+            .clone()
+    }
+
+    fn xs(&self) -> MutexGuard<'_, HashMap<(Tz, i32), String>> {
+        self.rules.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A zone's rules as a POSIX TZ string.
+pub fn posix_tz(tz: Tz, now: DateTime<Utc>) -> String {
+    // A year in which the rules changed has some other number of transitions, so go by the next.
+    let year = now.year();
+    let transitions = [year, year + 1]
+        .into_iter()
+        .map(|year| transitions(tz, year))
+        .find(|t| t.is_empty() || t.len() == 2);
+
+    let Some([a, b]) = transitions.as_deref() else {
+        let period = Period::at(tz, now);
+        return format!("{}{}", period.name(), hms(-period.utc_offset));
+    };
+
+    let (start, end) = match Period::at(tz, *a).dst {
+        true => (*a, *b),
+        false => (*b, *a),
+    };
+    let (std, dst) = (Period::at(tz, end), Period::at(tz, start));
+
+    let mut posix = format!("{}{}{}", std.name(), hms(-std.utc_offset), dst.name());
+    if dst.utc_offset != std.utc_offset + 3600 {
+        posix += &hms(-dst.utc_offset);
+    }
+    format!(
+        "{posix},{},{}",
+        rule(start, std.utc_offset),
+        rule(end, dst.utc_offset)
+    )
+}
+
+/// A fixed offset as a POSIX TZ string, for when there's no zone to go by.
+pub fn posix_fixed(offset: FixedOffset) -> String {
+    let east = offset.fix().local_minus_utc().into();
+    match east {
+        0 => "UTC0".to_string(),
+        east => format!("{}{}", numeric_name(east), hms(-east)),
+    }
+}
+
+/// The instants in a year at which a zone's offset or abbreviation changes.
+fn transitions(tz: Tz, year: i32) -> Vec<DateTime<Utc>> {
+    let start = Utc.with_ymd_and_hms(year, 1, 1, 0, 0, 0).unwrap();
+    let end = Utc.with_ymd_and_hms(year + 1, 1, 1, 0, 0, 0).unwrap();
+
+    let mut transitions = Vec::new();
+    let mut at = start;
+    while at < end {
+        let next = at + Duration::hours(1);
+        if Period::at(tz, at) != Period::at(tz, next) {
+            // Narrow it down to the second.
+            let (mut before, mut after) = (at, next);
+            while after - before > Duration::seconds(1) {
+                let mid = before + (after - before) / 2;
+                match Period::at(tz, before) == Period::at(tz, mid) {
+                    true => before = mid,
+                    false => after = mid,
+                }
+            }
+            transitions.push(after);
+        }
+        at = next;
+    }
+    transitions
+}
+
+/// When a transition happens, in the local time in effect up to it.
+fn rule(at: DateTime<Utc>, utc_offset: i64) -> String {
+    let local = at.naive_utc() + Duration::seconds(utc_offset);
+    let date = local.date();
+    let day = date.day();
+
+    let week = match day + 7 > days_in_month(date) {
+        true => 5,
+        false => (day - 1) / 7 + 1,
+    };
+    let weekday = date.weekday().num_days_from_sunday();
+    let secs = (local - date.and_hms_opt(0, 0, 0).unwrap()).num_seconds();
+
+    let mut rule = format!("M{}.{week}.{weekday}", date.month());
+    if secs != DEFAULT_TRANSITION_SECS {
+        rule += &format!("/{}", hms(secs));
+    }
+    rule
+}
+
+fn days_in_month(date: NaiveDate) -> u32 {
+    let (year, month) = match date.month() {
+        12 => (date.year() + 1, 1),
+        month => (date.year(), month + 1),
+    };
+    NaiveDate::from_ymd_opt(year, month, 1)
+        .unwrap()
+        .pred_opt()
+        .unwrap()
+        .day()
+}
+
+/// `[-]h[:mm[:ss]]`, as POSIX offsets and times are written.
+fn hms(secs: i64) -> String {
+    let sign = if secs < 0 { "-" } else { "" };
+    let secs = secs.abs();
+    let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+    match (m, s) {
+        (0, 0) => format!("{sign}{h}"),
+        (m, 0) => format!("{sign}{h}:{m:02}"),
+        (m, s) => format!("{sign}{h}:{m:02}:{s:02}"),
+    }
+}
+
+/// A name for an offset with no abbreviation, e.g. `<+0530>`.
+fn numeric_name(east: i64) -> String {
+    let sign = if east < 0 { '-' } else { '+' };
+    let (h, m) = (east.abs() / 3600, east.abs() / 60 % 60);
+    match m {
+        0 => format!("<{sign}{h:02}>"),
+        m => format!("<{sign}{h:02}{m:02}>"),
+    }
+}
+
+/// An RFC 3339 time, or a local time in `zone`. When the clocks go back, a local time that
+/// happens twice is taken as the first.
+pub fn instant(time: &str, zone: Option<Tz>) -> Result<DateTime<Utc>, String> {
+    if let Ok(at) = DateTime::parse_from_rfc3339(time) {
+        return Ok(at.to_utc());
+    }
+    let local = NaiveDateTime::parse_from_str(time, "%Y-%m-%dT%H:%M:%S%.f")
+        .or_else(|_| NaiveDateTime::parse_from_str(time, "%Y-%m-%dT%H:%M"))
+        .map_err(|_| format!("{time:?} isn't an RFC 3339 or local time."))?;
+
+    let at = match zone {
+        Some(zone) => zone
+            .from_local_datetime(&local)
+            .earliest()
+            .map(|t| t.to_utc()),
+        None => Local
+            .from_local_datetime(&local)
+            .earliest()
+            .map(|t| t.to_utc()),
+    };
+    at.ok_or_else(|| format!("{time} is skipped when the clocks go forward."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn posix(zone: Tz) -> String {
+        posix_tz(zone, Utc.with_ymd_and_hms(2026, 6, 1, 0, 0, 0).unwrap())
+    }
+
+    #[test]
+    fn north_american_dst() {
+        assert_eq!(posix(Tz::America__Los_Angeles), "PST8PDT,M3.2.0,M11.1.0");
+        assert_eq!(posix(Tz::America__New_York), "EST5EDT,M3.2.0,M11.1.0");
+    }
+
+    #[test]
+    fn european_dst_changes_at_one_utc() {
+        assert_eq!(posix(Tz::Europe__London), "GMT0BST,M3.5.0/1,M10.5.0");
+        assert_eq!(posix(Tz::Europe__Berlin), "CET-1CEST,M3.5.0,M10.5.0/3");
+    }
+
+    #[test]
+    fn southern_hemisphere_dst() {
+        assert_eq!(posix(Tz::Australia__Sydney), "AEST-10AEDT,M10.1.0,M4.1.0/3");
+    }
+
+    #[test]
+    fn half_hour_dst() {
+        assert_eq!(
+            posix(Tz::Australia__Lord_Howe),
+            "<+1030>-10:30<+11>-11,M10.1.0,M4.1.0"
+        );
+    }
+
+    #[test]
+    fn no_dst() {
+        assert_eq!(posix(Tz::Asia__Tokyo), "JST-9");
+        assert_eq!(posix(Tz::Asia__Kolkata), "IST-5:30");
+        assert_eq!(posix(Tz::UTC), "UTC0");
+    }
+
+    #[test]
+    fn numeric_abbreviations_are_bracketed() {
+        assert_eq!(posix(Tz::America__Sao_Paulo), "<-03>3");
+        assert_eq!(posix(Tz::Asia__Dubai), "<+04>-4");
+    }
+
+    #[test]
+    fn fixed_offsets() {
+        assert_eq!(posix_fixed(FixedOffset::east_opt(0).unwrap()), "UTC0");
+        assert_eq!(
+            posix_fixed(FixedOffset::west_opt(8 * 3600).unwrap()),
+            "<-08>8"
+        );
+        assert_eq!(
+            posix_fixed(FixedOffset::east_opt(5 * 3600 + 1800).unwrap()),
+            "<+0530>-5:30"
+        );
+    }
+
+    #[test]
+    fn rules_are_kept_per_year() {
+        let rules = Rules::default();
+        let june = Utc.with_ymd_and_hms(2026, 6, 1, 0, 0, 0).unwrap();
+        let december = Utc.with_ymd_and_hms(2026, 12, 1, 0, 0, 0).unwrap();
+        let zone = Tz::Europe__London;
+
+        assert_eq!(rules.posix_tz(zone, june), posix_tz(zone, june));
+        assert_eq!(rules.posix_tz(zone, december), posix_tz(zone, june));
+        rules.posix_tz(zone, Utc.with_ymd_and_hms(2027, 1, 1, 0, 0, 0).unwrap());
+        assert_eq!(rules.xs().len(), 2);
+    }
+}